@@ -1,6 +1,3 @@
-// EOF Magic detection.
-const DETECTION_SIZE = 40;
-
 let lastURL = "";
 
 const $progress = document.getElementById("progress");
@@ -21,17 +18,35 @@ const getMagic = (u8) => {
   }, 0);
 };
 
+/**
+ * 取出按顺序拼接后的前 `size` 个字节；数据不足时返回的更短。
+ * @param  {Uint8Array[]} parts 按顺序排列的数据块
+ * @param  {number} size 需要的字节数
+ * @return {Uint8Array}
+ */
+function headOf(parts, size) {
+  const head = new Uint8Array(size);
+  let filled = 0;
+  for (const part of parts) {
+    if (filled >= size) break;
+    const len = Math.min(part.length, size - filled);
+    head.set(part.subarray(0, len), filled);
+    filled += len;
+  }
+  return head.subarray(0, filled);
+}
+
 /**
  * 根据解密后的文件解析，获得新的文件名及对应 mimetype。
- * @param  {Uint8Array[]} u8Array 解密后的文件片段集合
+ * @param  {Uint8Array} header 解密后的文件开头
  * @param  {string} fileName 原始文件名
  * @return {[string, string]} 新的文件名以及 mimetype。
  */
-function fileDetection(u8Array, fileName) {
+function fileDetection(header, fileName) {
   const oggMagic = 0x5367674f;
   const flacMagic = 0x43614c66;
 
-  const magic = getMagic(u8Array[0]);
+  const magic = getMagic(header);
 
   // 未能识别时的返回内容
   let ext = ".bin";
@@ -55,69 +70,52 @@ function fileDetection(u8Array, fileName) {
 /**
  * 解密一个 QMC2 加密的文件。
  *
+ * 文件通过 `File.stream()` 逐块读入并解密，不需要一次性读入内存。
  * 如果检测并解密成功，返回解密后的 Uint8Array 数组，按顺序拼接即可得到完整文件。
  * 若失败，返回 `null`。
- * @param  {ArrayBuffer} mggBlob 读入的文件 ArrayBuffer
+ * @param  {File} file 需要解密的文件
  * @return {Promise<Uint8Array[]|null>}
  */
-async function decryptMGG(mggBlob) {
+async function decryptMGG(file) {
   // 初始化模组
   const QMCCrypto = window.QMCCrypto = window.QMCCrypto || await QMC2CryptoModule();
 
-  // 申请内存块，并文件末端数据到 WASM 的内存堆
-  const detectionBuf = new Uint8Array(mggBlob.slice(-DETECTION_SIZE));
-  let detectionResult;
+  // 读取文件末端数据，用于检测与提取 EKey
+  const tailSize = Math.min(file.size, QMCCrypto.get_recommended_tail_size());
+  const tail = new Uint8Array(await file.slice(-tailSize).arrayBuffer());
+  let decryptor;
   try {
-    detectionResult = QMCCrypto.detect(detectionBuf);
+    decryptor = QMCCrypto.QMC2StreamDecryptor.from_tail(file.size, tail);
   } catch (e) {
     alert("不支持的加密格式：" + e);
     return null;
   }
-  const position = detectionResult.ekey_position;
-  const len = detectionResult.ekey_len;
-  const songId = detectionResult.get_song_id();
-  console.info("Detected song id: %s", songId);
-  detectionResult.free();
-
-  // 计算解密后文件的大小。
-  // 之前得到的 position 为相对当前检测数据起点的偏移。
-  const decryptedSize = mggBlob.byteLength - DETECTION_SIZE + position;
-  $progress.max = decryptedSize;
-
-  // 提取嵌入到文件的 EKey
-  const ekey = new Uint8Array(
-    mggBlob.slice(decryptedSize, decryptedSize + len)
-  );
-
-  // 解码 UTF-8 数据到 string
-  const decoder = new TextDecoder();
-  const ekey_b64 = decoder.decode(ekey);
-
-  // 初始化加密与缓冲区
-  const crypto = QMCCrypto.decrypt_factory(ekey_b64);
+  console.info("Detected song id: %s", decryptor.get_song_id());
+  $progress.max = decryptor.get_audio_size();
 
   const decryptedParts = [];
-  let offset = 0;
-  let bytesToDecrypt = decryptedSize;
-  const DECRYPTION_BUF_SIZE = crypto.get_recommended_block_size();
-  while (bytesToDecrypt > 0) {
-    const blockSize = Math.min(bytesToDecrypt, DECRYPTION_BUF_SIZE);
-
-    // 解密一些片段
-    const blockData = new Uint8Array(
-      mggBlob.slice(offset, offset + blockSize)
-    );
-    crypto.decrypt(offset, blockData);
-    decryptedParts.push(blockData);
-
-    offset += blockSize;
-    bytesToDecrypt -= blockSize;
-    $progress.value = offset;
-
-    // 避免网页卡死，让 event loop 处理一下其它事件。
-    await new Promise((resolve) => setTimeout(resolve));
+  const decrypted = file.stream().pipeThrough(
+    new TransformStream({
+      transform(chunk, controller) {
+        const data = decryptor.transform(chunk);
+        if (data.length > 0) {
+          controller.enqueue(data);
+        }
+        $progress.value = Math.min(decryptor.get_position(), $progress.max);
+      },
+    })
+  );
+
+  try {
+    const reader = decrypted.getReader();
+    for (;;) {
+      const { done, value } = await reader.read();
+      if (done) break;
+      decryptedParts.push(value);
+    }
+  } finally {
+    decryptor.free();
   }
-  crypto.free();
 
   return decryptedParts;
 }
@@ -129,47 +127,41 @@ async function decryptMGG(mggBlob) {
 function processFile(file) {
   setInProgress(true);
   const fileName = file.name;
-  const reader = new FileReader();
-  reader.addEventListener("abort", () => {
-    setInProgress(false);
-  });
-  reader.addEventListener("error", (err) => {
-    setInProgress(false);
-    console.error(err);
-    alert("读取文件失败: " + err.message);
-  });
-  reader.addEventListener("load", (e) => {
-    if (lastURL) {
-      URL.revokeObjectURL(lastURL);
-      lastURL = "";
-    }
 
-    decryptMGG(e.target.result)
-      .then((decryptedParts) => {
-        if (!decryptedParts) return;
-
-        const [newFileName, mimeType] = fileDetection(decryptedParts, fileName);
-
-        const blob = new Blob(decryptedParts, {
-          type: mimeType,
-        });
-
-        const url = (lastURL = window.URL.createObjectURL(blob));
-        $player.src = url;
-
-        $dl.href = url;
-        $dl.textContent = newFileName;
-        $dl.download = newFileName;
-      })
-      .catch((err) => {
-        console.error(err);
-        alert("解密失败: \n" + err.message);
-      })
-      .then(() => {
-        setInProgress(false);
+  if (lastURL) {
+    URL.revokeObjectURL(lastURL);
+    lastURL = "";
+  }
+
+  decryptMGG(file)
+    .then((decryptedParts) => {
+      if (!decryptedParts) return;
+      if (decryptedParts.length === 0) {
+        throw new Error("文件中没有音频数据。");
+      }
+
+      // 第一块可能不足 4 字节，需从拼接后的开头检测。
+      const header = headOf(decryptedParts, 4);
+      const [newFileName, mimeType] = fileDetection(header, fileName);
+
+      const blob = new Blob(decryptedParts, {
+        type: mimeType,
       });
-  });
-  reader.readAsArrayBuffer(file);
+
+      const url = (lastURL = window.URL.createObjectURL(blob));
+      $player.src = url;
+
+      $dl.href = url;
+      $dl.textContent = newFileName;
+      $dl.download = newFileName;
+    })
+    .catch((err) => {
+      console.error(err);
+      alert("解密失败: \n" + err.message);
+    })
+    .then(() => {
+      setInProgress(false);
+    });
 }
 
 function main() {
@@ -0,0 +1,351 @@
+use crate::decrypt::{audio_len_of, decrypt_file, output_extension, output_path_in, FileOptions};
+use crate::manifest::{file_stamp, hash_file, Entry, Manifest};
+use crate::output::remove_output;
+use crate::progress::{info, ProgressBar};
+use std::collections::{HashMap, HashSet};
+use std::error::Error;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+/// Save the manifest every so many decrypted files, so an interrupted batch
+/// keeps most of its progress.
+const SAVE_INTERVAL: usize = 50;
+
+pub struct BatchOptions {
+    pub input_dir: PathBuf,
+    pub output_dir: PathBuf,
+    /// Remove outputs whose input has gone away.
+    pub prune: bool,
+    /// Read inputs through memory maps and decrypt on every core.
+    pub mmap: bool,
+}
+
+#[derive(Default)]
+struct Summary {
+    decrypted: usize,
+    unchanged: usize,
+    failed: usize,
+    pruned: usize,
+}
+
+/// Decrypt everything under `options.input_dir` into the same layout under
+/// `options.output_dir`, skipping files a previous run already handled.
+pub fn batch(options: &BatchOptions) -> Result<(), Box<dyn Error>> {
+    fs::create_dir_all(&options.output_dir)?;
+    let mut manifest = Manifest::load(&options.output_dir)?;
+
+    let mut inputs = vec![];
+    let output_dir = options.output_dir.canonicalize()?;
+    collect_inputs(&options.input_dir, &output_dir, &mut inputs)?;
+    inputs.sort();
+
+    // Progress within a file counts audio bytes, so the totals must too.
+    // Working them out opens every file, so only when they will be shown, and
+    // only for files the manifest does not already vouch for.
+    let drawn = ProgressBar::is_drawn();
+    let audio_lens: Vec<u64> = inputs
+        .iter()
+        .map(|path| {
+            if !drawn {
+                return 0;
+            }
+            let relative = path.strip_prefix(&options.input_dir).unwrap_or(path);
+            let unchanged = manifest
+                .entries
+                .get(&to_key(relative))
+                .is_some_and(|entry| is_unchanged(options, entry, path));
+            if unchanged {
+                0
+            } else {
+                audio_len_of(path)
+            }
+        })
+        .collect();
+    let mut progress = ProgressBar::for_batch(inputs.len(), audio_lens.iter().sum());
+
+    // Which input each output belongs to, so that two inputs named alike
+    // (e.g. `a.mflac` and `a.mflac0`) never take turns at the same output.
+    // Outputs of inputs that have gone away are up for grabs.
+    let keys: HashSet<String> = inputs
+        .iter()
+        .filter_map(|path| path.strip_prefix(&options.input_dir).ok())
+        .map(to_key)
+        .collect();
+    let mut owners: HashMap<String, String> = manifest
+        .entries
+        .iter()
+        .filter(|(key, _)| keys.contains(*key))
+        .map(|(key, entry)| (entry.output.clone(), key.clone()))
+        .collect();
+
+    let mut summary = Summary::default();
+    let mut seen = HashSet::new();
+    for (input_path, &audio_len) in inputs.iter().zip(&audio_lens) {
+        let relative = input_path.strip_prefix(&options.input_dir)?;
+        let key = to_key(relative);
+
+        match process(
+            options,
+            &mut manifest,
+            &mut owners,
+            &key,
+            input_path,
+            relative,
+            &mut progress,
+        ) {
+            Ok(true) => {
+                progress.finish_file(audio_len);
+                summary.decrypted += 1;
+                if summary.decrypted % SAVE_INTERVAL == 0 {
+                    manifest.save(&options.output_dir)?;
+                }
+            }
+            Ok(false) => {
+                progress.skip_file(audio_len);
+                summary.unchanged += 1;
+            }
+            Err(err) => {
+                progress.finish_file(audio_len);
+                summary.failed += 1;
+                eprintln!("error: {}: {}", input_path.display(), err);
+            }
+        }
+
+        seen.insert(key);
+    }
+
+    if options.prune {
+        summary.pruned = prune(&options.output_dir, &mut manifest, &seen)?;
+    }
+    manifest.save(&options.output_dir)?;
+
+    info!(
+        "{} decrypted, {} unchanged, {} failed, {} pruned.",
+        summary.decrypted, summary.unchanged, summary.failed, summary.pruned
+    );
+
+    if summary.failed > 0 {
+        return Err(format!("{} file(s) could not be decrypted", summary.failed).into());
+    }
+    Ok(())
+}
+
+/// Decrypt a single input unless the manifest says it is up to date.
+///
+/// Returns whether anything was decrypted.
+fn process(
+    options: &BatchOptions,
+    manifest: &mut Manifest,
+    owners: &mut HashMap<String, String>,
+    key: &str,
+    input_path: &Path,
+    relative: &Path,
+    progress: &mut ProgressBar,
+) -> Result<bool, Box<dyn Error>> {
+    let (size, mtime_ns) = file_stamp(input_path)?;
+    let output_exists = |entry: &Entry| options.output_dir.join(&entry.output).is_file();
+
+    if let Some(entry) = manifest.entries.get(key) {
+        if is_unchanged(options, entry, input_path) {
+            return Ok(false);
+        }
+    }
+
+    // Size or time changed; only the content can tell whether it really did.
+    let sha256 = hash_file(input_path)?;
+    if let Some(entry) = manifest.entries.get_mut(key) {
+        if entry.sha256 == sha256 && output_exists(entry) {
+            entry.size = size;
+            entry.mtime_ns = mtime_ns;
+            return Ok(false);
+        }
+    }
+
+    let relative_dir = relative.parent().unwrap_or_else(|| Path::new(""));
+    let relative_output = output_path_in(input_path, relative_dir).ok_or("unsupported file")?;
+    let output = to_key(&relative_output);
+    match owners.get(&output) {
+        Some(owner) if owner != key => {
+            return Err(format!("{} is already the output of {}", output, owner).into());
+        }
+        _ => {}
+    }
+    let output_path = options.output_dir.join(&relative_output);
+    if let Some(dir) = output_path.parent() {
+        fs::create_dir_all(dir)?;
+    }
+
+    info!("{} -> {}", input_path.display(), output_path.display());
+    let file_options = FileOptions {
+        mmap: options.mmap,
+        ..Default::default()
+    };
+    let report = decrypt_file(input_path, &output_path, &file_options, progress)?;
+
+    owners.insert(output.clone(), key.to_string());
+    let previous = manifest.entries.insert(
+        key.to_string(),
+        Entry {
+            size,
+            mtime_ns,
+            song_id: report.song_id,
+            sha256,
+            output: output.clone(),
+        },
+    );
+
+    // The output got another name, e.g. from the format in the metadata.
+    if let Some(previous) = previous.filter(|previous| previous.output != output) {
+        if owners
+            .get(&previous.output)
+            .is_some_and(|owner| owner == key)
+        {
+            owners.remove(&previous.output);
+            let previous_path = options.output_dir.join(&previous.output);
+            info!("removing {}", previous_path.display());
+            remove_output(&previous_path)?;
+        }
+    }
+    Ok(true)
+}
+
+/// Whether `input_path` still has the size and time recorded in `entry`, and
+/// its output is still there.
+fn is_unchanged(options: &BatchOptions, entry: &Entry, input_path: &Path) -> bool {
+    file_stamp(input_path).is_ok_and(|(size, mtime_ns)| {
+        entry.size == size
+            && entry.mtime_ns == mtime_ns
+            && options.output_dir.join(&entry.output).is_file()
+    })
+}
+
+/// Drop manifest entries (and their outputs) for inputs that no longer exist.
+fn prune(
+    output_dir: &Path,
+    manifest: &mut Manifest,
+    seen: &HashSet<String>,
+) -> Result<usize, Box<dyn Error>> {
+    let gone: Vec<String> = manifest
+        .entries
+        .keys()
+        .filter(|key| !seen.contains(*key))
+        .cloned()
+        .collect();
+
+    for key in &gone {
+        let entry = manifest.entries.remove(key).unwrap();
+        // Another input may have taken over the output since.
+        if manifest
+            .entries
+            .values()
+            .any(|other| other.output == entry.output)
+        {
+            continue;
+        }
+        let output_path = output_dir.join(&entry.output);
+        info!("pruning {}", output_path.display());
+        remove_output(&output_path)?;
+    }
+
+    Ok(gone.len())
+}
+
+fn collect_inputs(
+    dir: &Path,
+    output_dir: &Path,
+    inputs: &mut Vec<PathBuf>,
+) -> Result<(), Box<dyn Error>> {
+    for entry in fs::read_dir(dir)? {
+        let entry = entry?;
+        let path = entry.path();
+        let file_type = entry.file_type()?;
+
+        if file_type.is_dir() {
+            // Don't walk into our own output when it lives inside the input.
+            if path.canonicalize()? != output_dir {
+                collect_inputs(&path, output_dir, inputs)?;
+            }
+        } else if file_type.is_file() && output_extension(&path).is_some() {
+            inputs.push(path);
+        }
+    }
+
+    Ok(())
+}
+
+/// Manifest paths always use `/`, so the manifest stays portable.
+fn to_key(path: &Path) -> String {
+    path.components()
+        .map(|c| c.as_os_str().to_string_lossy())
+        .collect::<Vec<_>>()
+        .join("/")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use qmc2_crypto::test_util::{qtag_file, test_audio, test_key, xm_file};
+
+    fn options(name: &str) -> BatchOptions {
+        let dir = std::env::temp_dir().join(format!("qmc2-{}-{}", name, std::process::id()));
+        fs::create_dir_all(dir.join("in")).unwrap();
+        BatchOptions {
+            input_dir: dir.join("in"),
+            output_dir: dir.join("out"),
+            prune: true,
+            mmap: false,
+        }
+    }
+
+    #[test]
+    fn test_to_key() {
+        let path: PathBuf = ["album", "disc 1", "song.mflac"].iter().collect();
+        assert_eq!(to_key(&path), "album/disc 1/song.mflac");
+    }
+
+    #[test]
+    fn test_refuses_shared_output() {
+        let options = options("batch-shared");
+        let audio = test_audio(b"fLaC", 1000);
+        let other = test_audio(b"fLaC", 2000);
+        fs::write(
+            options.input_dir.join("a.mflac"),
+            qtag_file(&audio, &test_key(512)),
+        )
+        .unwrap();
+        fs::write(
+            options.input_dir.join("a.mflac0"),
+            qtag_file(&other, &test_key(512)),
+        )
+        .unwrap();
+
+        assert!(batch(&options).is_err());
+        assert_eq!(fs::read(options.output_dir.join("a.flac")).unwrap(), audio);
+        let manifest = Manifest::load(&options.output_dir).unwrap();
+        assert_eq!(manifest.entries.keys().collect::<Vec<_>>(), ["a.mflac"]);
+
+        // Once the first is gone, the other may have the output, and pruning
+        // the first must not take it away again.
+        fs::remove_file(options.input_dir.join("a.mflac")).unwrap();
+        batch(&options).unwrap();
+        assert_eq!(fs::read(options.output_dir.join("a.flac")).unwrap(), other);
+
+        fs::remove_dir_all(options.input_dir.parent().unwrap()).unwrap();
+    }
+
+    #[test]
+    fn test_removes_renamed_output() {
+        let options = options("batch-renamed");
+        let input_path = options.input_dir.join("b.xm");
+        fs::write(&input_path, xm_file(b" MP3", 0, 0x5a, &[1; 100])).unwrap();
+        batch(&options).unwrap();
+        assert!(options.output_dir.join("b.mp3").is_file());
+
+        fs::write(&input_path, xm_file(b"FLAC", 0, 0x5a, &[1; 200])).unwrap();
+        batch(&options).unwrap();
+        assert!(options.output_dir.join("b.flac").is_file());
+        assert!(!options.output_dir.join("b.mp3").exists());
+
+        fs::remove_dir_all(options.input_dir.parent().unwrap()).unwrap();
+    }
+}
@@ -0,0 +1,349 @@
+use crate::output::AtomicOutput;
+use crate::progress::{info, ProgressBar};
+use qmc2_crypto::errors::DecryptError;
+use qmc2_crypto::format::{FileReport, Registry};
+use qmc2_crypto::joox::JooxFormat;
+use qmc2_crypto::stream::Options;
+use std::cell::RefCell;
+use std::error::Error;
+use std::fs::File;
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::sync::OnceLock;
+
+/// How much of a partial output to re-check before resuming from it.
+const RESUME_VERIFY_SIZE: u64 = 64 * 1024;
+
+static REGISTRY: OnceLock<Registry> = OnceLock::new();
+
+/// The formats every command decrypts.
+pub fn registry() -> &'static Registry {
+    REGISTRY.get_or_init(Registry::default)
+}
+
+/// Also decrypt JOOX files downloaded by the device with `joox_uuid`, if given.
+///
+/// Must be called before anything uses `registry()`.
+pub fn init_registry(joox_uuid: Option<&str>) {
+    let mut registry = Registry::default();
+    if let Some(uuid) = joox_uuid {
+        registry.register(JooxFormat::new(uuid));
+    }
+    let _ = REGISTRY.set(registry);
+}
+
+/// Extension of `path` (without the dot), or an empty string.
+pub fn extension_of(path: &Path) -> &str {
+    path.extension().and_then(|ext| ext.to_str()).unwrap_or("")
+}
+
+/// Size of the audio in `path` once decrypted, or `0` if it cannot be opened.
+///
+/// This is what progress counts, rather than the size of the file.
+pub fn audio_len_of(path: &Path) -> u64 {
+    let Ok(mut file) = File::open(path) else {
+        return 0;
+    };
+    registry()
+        .open(&mut file, extension_of(path))
+        .map_or(0, |(_, opened)| opened.audio_len)
+}
+
+/// Extension of the decrypted file, if `path` looks like something we can decrypt.
+pub fn output_extension(path: &Path) -> Option<&'static str> {
+    registry().decrypted_extension(path.extension()?.to_str()?)
+}
+
+/// Where the decrypted copy of `input_path` goes inside `output_dir`.
+pub fn output_path_in(input_path: &Path, output_dir: &Path) -> Option<PathBuf> {
+    let ext = match output_extension(input_path)? {
+        // Only the content can tell: what the header or metadata says if
+        // anything, or else what the start of the audio looks like.
+        "" => {
+            let mut file = File::open(input_path).ok()?;
+            let ext = extension_of(input_path);
+            let (_, opened) = registry().open(&mut file, ext).ok()?;
+            // Comes from the file, so only taken if it looks like an extension.
+            let named = opened.metadata.format.filter(|format| {
+                !format.is_empty() && format.bytes().all(|b| b.is_ascii_alphanumeric())
+            });
+            match named {
+                Some(format) => format.to_ascii_lowercase(),
+                None => registry()
+                    .sniff(&mut file, ext)
+                    .ok()?
+                    .extension()
+                    .to_string(),
+            }
+        }
+        ext => ext.to_string(),
+    };
+    let mut name = input_path.file_stem()?.to_os_string();
+    name.push(".");
+    name.push(ext);
+    Some(output_dir.join(name))
+}
+
+/// How `decrypt_file` goes about it.
+#[derive(Clone, Copy, Default)]
+pub struct FileOptions {
+    /// Continue an interrupted run from its `.part` file.
+    pub resume: bool,
+    /// Read the input through a memory map and decrypt on every core.
+    pub mmap: bool,
+}
+
+/// Decrypt `input_path` to `output_path`.
+pub fn decrypt_file(
+    input_path: &Path,
+    output_path: &Path,
+    options: &FileOptions,
+    progress: &mut ProgressBar,
+) -> Result<FileReport, Box<dyn Error>> {
+    let mut input_file = File::open(input_path)?;
+    let ext = extension_of(input_path);
+    // Fail on an input we cannot decrypt before creating the `.part` file.
+    registry().open(&mut input_file, ext)?;
+    let mut output = AtomicOutput::open(output_path, options.resume)?;
+
+    let report = if options.mmap {
+        output.truncate(0)?;
+        decrypt_mapped(&input_file, ext, &mut output, progress)?
+    } else {
+        decrypt_streamed(&mut input_file, ext, &mut output, options.resume, progress)?
+    };
+
+    output.commit()?;
+
+    progress.clear();
+    print_report(&report);
+    Ok(report)
+}
+
+pub fn print_report(report: &FileReport) {
+    if report.song_id.is_empty() {
+        info!("song id: (not found)");
+    } else {
+        info!("song id: {}", report.song_id);
+    }
+
+    let metadata = &report.metadata;
+    if let Some(title) = &metadata.title {
+        info!("title: {}", title);
+    }
+    if !metadata.artists.is_empty() {
+        info!("artist: {}", metadata.artists.join(", "));
+    }
+    if let Some(album) = &metadata.album {
+        info!("album: {}", album);
+    }
+    if let Some(bitrate) = metadata.bitrate {
+        info!("bitrate: {} kbit/s", bitrate);
+    }
+    if let Some(cover) = &metadata.cover {
+        info!("cover: {} bytes", cover.len());
+    }
+}
+
+fn decrypt_streamed(
+    input_file: &mut File,
+    ext: &str,
+    output: &mut AtomicOutput,
+    resume: bool,
+    progress: &mut ProgressBar,
+) -> Result<FileReport, Box<dyn Error>> {
+    let progress = RefCell::new(progress);
+    let on_progress = |p| progress.borrow_mut().update(p);
+    let options = Options {
+        on_progress: Some(&on_progress),
+        ..Default::default()
+    };
+
+    let part_len = output.len()?;
+    let resumed = match resume && part_len > 0 {
+        true => try_resume(input_file, ext, output, part_len, &options)?,
+        false => None,
+    };
+    match resumed {
+        Some(report) => Ok(report),
+        None => {
+            output.truncate(0)?;
+            Ok(registry().decrypt_stream(input_file, output, ext, &options)?)
+        }
+    }
+}
+
+#[cfg(not(target_os = "wasi"))]
+fn decrypt_mapped(
+    input_file: &File,
+    ext: &str,
+    output: &mut AtomicOutput,
+    progress: &mut ProgressBar,
+) -> Result<FileReport, Box<dyn Error>> {
+    crate::mapped::decrypt_mapped(input_file, ext, output, progress)
+}
+
+#[cfg(target_os = "wasi")]
+fn decrypt_mapped(
+    _input_file: &File,
+    _ext: &str,
+    _output: &mut AtomicOutput,
+    _progress: &mut ProgressBar,
+) -> Result<FileReport, Box<dyn Error>> {
+    Err("--mmap is not available in the WASI build".into())
+}
+
+/// Continue an interrupted run from the end of its partial output.
+///
+/// The tail of the partial output is decrypted again and compared first, so
+/// a `.part` file left by a crash (or by a different input) is thrown away
+/// instead of being silently extended. Returns `None` in that case.
+fn try_resume(
+    input_file: &mut File,
+    ext: &str,
+    output: &mut AtomicOutput,
+    part_len: u64,
+    options: &Options,
+) -> Result<Option<FileReport>, Box<dyn Error>> {
+    let verify_len = std::cmp::min(part_len, RESUME_VERIFY_SIZE);
+    let verify_start = part_len - verify_len;
+
+    let mut written = vec![0u8; verify_len as usize];
+    output.read_back(verify_start, &mut written)?;
+
+    let mut writer = VerifyingWriter {
+        output,
+        expected: written,
+        verified: 0,
+        mismatch: false,
+    };
+    let options = Options {
+        start: verify_start,
+        ..*options
+    };
+
+    match registry().decrypt_stream(input_file, &mut writer, ext, &options) {
+        Ok(_) if writer.verified < writer.expected.len() => {
+            info!("partial output is longer than the audio, starting over.");
+            Ok(None)
+        }
+        Ok(mut report) => {
+            info!("resumed from byte {}", part_len);
+            // Only count what was appended to the partial output.
+            report.bytes_written -= writer.verified as u64;
+            Ok(Some(report))
+        }
+        Err(DecryptError::StartOutOfRange { .. }) => Ok(None),
+        Err(_) if writer.mismatch => {
+            info!("partial output does not match the input, starting over.");
+            Ok(None)
+        }
+        Err(err) => Err(err.into()),
+    }
+}
+
+/// Checks the decrypted data against what is already in the partial output,
+/// and only appends what comes after it.
+struct VerifyingWriter<'a> {
+    output: &'a mut AtomicOutput,
+    expected: Vec<u8>,
+    verified: usize,
+    mismatch: bool,
+}
+
+impl Write for VerifyingWriter<'_> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let to_verify = std::cmp::min(buf.len(), self.expected.len() - self.verified);
+        if to_verify == 0 {
+            return self.output.write(buf);
+        }
+
+        if buf[..to_verify] != self.expected[self.verified..self.verified + to_verify] {
+            self.mismatch = true;
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "partial output does not match the input",
+            ));
+        }
+
+        self.verified += to_verify;
+        Ok(to_verify)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.output.flush()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use qmc2_crypto::test_util::{xm_file, SAWTOOTH_FLAC};
+
+    #[test]
+    fn test_output_path_in() {
+        let out = Path::new("out");
+        assert_eq!(
+            output_path_in(Path::new("in/a.mflac"), out),
+            Some(PathBuf::from("out/a.flac"))
+        );
+        assert_eq!(
+            output_path_in(Path::new("in/b.MGG1"), out),
+            Some(PathBuf::from("out/b.ogg"))
+        );
+        assert_eq!(
+            output_path_in(Path::new("in/c.d.mmp4"), out),
+            Some(PathBuf::from("out/c.d.mp4"))
+        );
+        assert_eq!(output_path_in(Path::new("in/c.flac"), out), None);
+        assert_eq!(output_path_in(Path::new("in/mflac"), out), None);
+    }
+
+    #[test]
+    fn test_output_path_from_metadata() {
+        // An `.xm` file left unencrypted, whose audio does not sniff as anything.
+        let unsniffable = |tag| xm_file(tag, 100, 0, &[0x5a; 100]);
+        let dir = std::env::temp_dir().join(format!("qmc2-naming-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let out = Path::new("out");
+
+        let path = dir.join("tagged.xm");
+        std::fs::write(&path, unsniffable(b" MP3")).unwrap();
+        assert_eq!(
+            output_path_in(&path, out),
+            Some(PathBuf::from("out/tagged.mp3"))
+        );
+
+        let path = dir.join("untagged.xm");
+        std::fs::write(&path, unsniffable(b"OGG ")).unwrap();
+        assert_eq!(
+            output_path_in(&path, out),
+            Some(PathBuf::from("out/untagged.bin"))
+        );
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_resume_over_long_part() {
+        let plain = SAWTOOTH_FLAC;
+        let dir = std::env::temp_dir().join(format!("qmc2-resume-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let input_path = dir.join("sawtooth.mflac");
+        std::fs::write(
+            &input_path,
+            include_bytes!("../../qmc2-crypto/fixtures/sawtooth.mflac"),
+        )
+        .unwrap();
+        let output_path = dir.join("sawtooth.flac");
+        // Right as far as it goes, but it goes past the end of the audio.
+        std::fs::write(dir.join("sawtooth.flac.part"), [plain, b"garbage"].concat()).unwrap();
+
+        let options = FileOptions {
+            resume: true,
+            ..Default::default()
+        };
+        decrypt_file(&input_path, &output_path, &options, &mut ProgressBar::new()).unwrap();
+        assert_eq!(std::fs::read(&output_path).unwrap(), plain);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
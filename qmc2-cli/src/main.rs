@@ -1,80 +1,350 @@
-use qmc2_crypto::detection::RECOMMENDED_DETECTION_SIZE;
-use std::fs::File;
-use std::io::{Read, Seek, SeekFrom, Write};
+mod batch;
+mod decrypt;
+mod manifest;
+#[cfg(not(target_os = "wasi"))]
+mod mapped;
+mod output;
+mod progress;
+mod rewrap;
+#[cfg(target_os = "linux")]
+mod watch;
+
+use progress::ProgressBar;
+use std::error::Error;
 use std::path::Path;
 
-const NEG_DETECTION_POS: i64 = -(RECOMMENDED_DETECTION_SIZE as i64);
+fn print_usage(program: &str) {
+    eprintln!(
+        "Usage: {} [--resume | --mmap] 'encrypted_input_path' 'decrypted_output_path'",
+        program
+    );
+    eprintln!("       {} --in-place 'encrypted_path'", program);
+    eprintln!(
+        "       {} batch 'input_dir' 'output_dir' [--prune] [--mmap]",
+        program
+    );
+    eprintln!(
+        "       {} rewrap 'encrypted_input_path' 'output_path' [--v1] [--song-id ID] [--rekey] [--key-len N]",
+        program
+    );
+    eprintln!(
+        "       {} watch 'input_dir' --out 'output_dir' [--settle SECONDS] [--delete | --move-to DIR]",
+        program
+    );
+    eprintln!();
+    eprintln!("Global options (before everything else):");
+    eprintln!("  -q, --quiet      only print errors");
+    eprintln!("  --joox-uuid ID   also decrypt JOOX files downloaded by the device with this UUID");
+    eprintln!();
+    eprintln!("Options:");
+    eprintln!("  --resume         continue an interrupted run from its '.part' file");
+    eprintln!("  --mmap           read through a memory map and decrypt on every core");
+    eprintln!("  --in-place       decrypt over the input and cut off its trailer (not resumable;");
+    eprintln!("                   an interrupted run leaves 'encrypted_path.in-place' behind)");
+    eprintln!();
+    eprintln!("Batch options:");
+    eprintln!("  --prune          remove outputs whose input is gone");
+    eprintln!("  --mmap           as above");
+    eprintln!();
+    eprintln!("Rewrap options:");
+    eprintln!("  --v1             write a v1 trailer (no song id) instead of a QTag one");
+    eprintln!("  --song-id ID     song id for the new trailer (default: keep the current one)");
+    eprintln!("  --rekey          re-encrypt under a freshly generated key");
+    eprintln!(
+        "  --key-len N      length of that key (default: {}); implies --rekey",
+        rewrap::DEFAULT_KEY_LEN
+    );
+    eprintln!();
+    eprintln!("Watch options (Linux only):");
+    eprintln!("  --out DIR        where to write decrypted files");
+    eprintln!(
+        "  --settle SECS    how long a file must stop growing before it is decrypted (default: 3)"
+    );
+    eprintln!("  --delete         delete the original once decrypted");
+    eprintln!("  --move-to DIR    move the original to DIR once decrypted");
+    eprintln!();
+}
+
+#[derive(Default)]
+struct GlobalOptions {
+    quiet: bool,
+    joox_uuid: Option<String>,
+}
+
+/// Parse the options in front of the command; returns them and the index of
+/// the first argument that is not one.
+fn parse_global_options(args: &[String]) -> Result<(GlobalOptions, usize), Box<dyn Error>> {
+    let mut options = GlobalOptions::default();
+    let mut i = 1;
+    while let Some(arg) = args.get(i) {
+        let value = || {
+            args.get(i + 1)
+                .cloned()
+                .ok_or_else(|| usage_error(&format!("{} needs a value", arg)))
+        };
+        match arg.as_str() {
+            "-q" | "--quiet" => options.quiet = true,
+            "--joox-uuid" => {
+                options.joox_uuid = Some(value()?);
+                i += 1;
+            }
+            _ => break,
+        }
+        i += 1;
+    }
+    Ok((options, i))
+}
 
 fn main() {
     let args: Vec<String> = std::env::args().collect();
-    eprintln!("QMC2-decoder (rust) v0.0.6 by Jixun");
-    eprintln!("Licensed under the MIT License & Apache License 2.0.");
-    eprintln!();
+    let result = parse_global_options(&args).and_then(|(options, first)| {
+        progress::set_quiet(options.quiet);
+        decrypt::init_registry(options.joox_uuid.as_deref());
+        run(&args[first..])
+    });
 
-    if args.len() < 3 {
-        eprintln!(
-            "Usage: {} 'encrypted_input_path' 'decrypted_output_path'",
-            args[0]
-        );
+    if let Err(err) = result {
         eprintln!();
-
+        eprintln!("error: {}", err);
+        if err.is::<UsageError>() {
+            eprintln!();
+            print_usage(&args[0]);
+        }
         std::process::exit(1);
     }
+}
+
+/// Run the command in `args`, which starts after the global options.
+fn run(args: &[String]) -> Result<(), Box<dyn Error>> {
+    progress::info!("QMC2-decoder (rust) v0.0.6 by Jixun");
+    progress::info!("Licensed under the MIT License & Apache License 2.0.");
+    progress::info!();
+
+    match args.first().map(String::as_str) {
+        Some("batch") => batch_command(&args[1..]),
+        Some("rewrap") => rewrap_command(&args[1..]),
+        Some("watch") => watch_command(&args[1..]),
+        _ => decrypt_command(args),
+    }
+}
+
+#[derive(Debug)]
+struct UsageError(String);
+
+impl std::fmt::Display for UsageError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+impl Error for UsageError {}
+
+fn usage_error(message: &str) -> Box<dyn Error> {
+    Box::new(UsageError(message.to_string()))
+}
+
+fn decrypt_command(args: &[String]) -> Result<(), Box<dyn Error>> {
+    let mut options = decrypt::FileOptions::default();
+    let mut in_place = false;
+    let mut paths = vec![];
+    for arg in args {
+        match arg.as_str() {
+            "--resume" => options.resume = true,
+            "--mmap" => options.mmap = true,
+            "--in-place" => in_place = true,
+            _ => paths.push(arg),
+        }
+    }
+
+    if in_place {
+        if options.resume || options.mmap {
+            return Err(usage_error(
+                "--in-place cannot be combined with other modes",
+            ));
+        }
+        if paths.len() != 1 {
+            return Err(usage_error("expected a single path with --in-place"));
+        }
+        return in_place_command(Path::new(paths[0]));
+    }
+
+    if options.resume && options.mmap {
+        return Err(usage_error("--resume cannot be combined with --mmap"));
+    }
+    if paths.len() != 2 {
+        return Err(usage_error("expected an input and an output path"));
+    }
+
+    let mut progress = ProgressBar::new();
+    let result = decrypt::decrypt_file(
+        Path::new(paths[0]),
+        Path::new(paths[1]),
+        &options,
+        &mut progress,
+    );
+    progress.clear();
+    result?;
+
+    progress::info!("done!");
+    Ok(())
+}
+
+#[cfg(not(target_os = "wasi"))]
+fn in_place_command(path: &Path) -> Result<(), Box<dyn Error>> {
+    let mut progress = ProgressBar::new();
+    let result = mapped::decrypt_in_place(path, &mut progress);
+    progress.clear();
+    decrypt::print_report(&result?);
+
+    progress::info!("done!");
+    Ok(())
+}
+
+#[cfg(target_os = "wasi")]
+fn in_place_command(_path: &Path) -> Result<(), Box<dyn Error>> {
+    Err("--in-place is not available in the WASI build".into())
+}
+
+fn batch_command(args: &[String]) -> Result<(), Box<dyn Error>> {
+    let mut prune = false;
+    let mut mmap = false;
+    let mut paths = vec![];
+    for arg in args {
+        match arg.as_str() {
+            "--prune" => prune = true,
+            "--mmap" => mmap = true,
+            _ => paths.push(arg),
+        }
+    }
 
-    let input_path = Path::new(&args[1]);
-    let output_path = Path::new(&args[2]);
+    if paths.len() != 2 {
+        return Err(usage_error("expected an input and an output directory"));
+    }
 
-    let mut detection_buf = vec![0u8; RECOMMENDED_DETECTION_SIZE];
+    batch::batch(&batch::BatchOptions {
+        input_dir: paths[0].into(),
+        output_dir: paths[1].into(),
+        prune,
+        mmap,
+    })
+}
 
-    let mut input_file = File::open(&input_path).unwrap();
+fn rewrap_command(args: &[String]) -> Result<(), Box<dyn Error>> {
+    use qmc2_crypto::trailer::TrailerKind;
+
+    let mut trailer = TrailerKind::QTag;
+    let mut song_id = None;
+    let mut key_len = None;
+    let mut paths = vec![];
+
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--v1" => trailer = TrailerKind::V1,
+            "--song-id" => {
+                let id = args
+                    .next()
+                    .ok_or_else(|| usage_error("--song-id needs a value"))?;
+                song_id = Some(id.as_str());
+            }
+            "--rekey" => {
+                key_len.get_or_insert(rewrap::DEFAULT_KEY_LEN);
+            }
+            "--key-len" => {
+                let len = args
+                    .next()
+                    .ok_or_else(|| usage_error("--key-len needs a number"))?;
+                let len = len
+                    .parse::<usize>()
+                    .ok()
+                    .filter(|len| *len >= 8)
+                    .ok_or_else(|| usage_error("--key-len must be at least 8"))?;
+                key_len = Some(len);
+            }
+            _ => paths.push(arg),
+        }
+    }
 
-    input_file.seek(SeekFrom::End(NEG_DETECTION_POS)).unwrap();
-    input_file.read_exact(&mut detection_buf).unwrap();
-    let detection = qmc2_crypto::detection::detect(&detection_buf).unwrap();
+    if matches!(trailer, TrailerKind::V1) && song_id.is_some() {
+        return Err(usage_error(
+            "--song-id cannot be combined with --v1, which has no song id",
+        ));
+    }
+    if paths.len() != 2 {
+        return Err(usage_error("expected an input and an output path"));
+    }
 
-    eprint!("song id: ");
-    if detection.song_id.is_empty() {
-        eprintln!("(not found)")
-    } else {
-        eprintln!("{}", detection.song_id);
+    let new_key = key_len.map(rewrap::generate_key).transpose()?;
+    let options = qmc2_crypto::RewrapOptions {
+        new_key: new_key.as_deref(),
+        song_id,
+        trailer,
+        ..Default::default()
     };
+    let report = rewrap::rewrap_file(Path::new(paths[0]), Path::new(paths[1]), &options)?;
+
+    progress::info!(
+        "{} -> {:?} trailer, {:?} cipher, {} bytes of audio",
+        paths[0],
+        trailer,
+        report.cipher,
+        report.audio_len
+    );
+    progress::info!("done!");
+    Ok(())
+}
 
-    input_file
-        .seek(SeekFrom::End(NEG_DETECTION_POS + detection.ekey_position))
-        .unwrap();
-
-    let mut ekey_buf = vec![0u8; detection.ekey_len];
-    input_file.read_exact(&mut ekey_buf).unwrap();
-    let ekey = std::str::from_utf8(&ekey_buf).unwrap();
-
-    let decryptor = qmc2_crypto::decrypt_factory(ekey).expect("Could not extract ekey");
-
-    let mut output_file = File::create(&output_path).unwrap();
-    input_file
-        .seek(SeekFrom::End(NEG_DETECTION_POS + detection.eof_position))
-        .unwrap();
-    let mut bytes_to_decrypt = input_file.stream_position().unwrap() as usize;
-    input_file.seek(SeekFrom::Start(0)).unwrap();
-
-    let mut offset = 0usize;
-    let mut buf = vec![0u8; decryptor.get_recommended_block_size()];
-
-    eprint!("Decrypting..");
-    while bytes_to_decrypt > 0 {
-        // Don't read over the file...
-        let expected_read_size = std::cmp::min(bytes_to_decrypt, buf.len());
-        let read_size = input_file.read(&mut buf[0..expected_read_size]).unwrap();
-        decryptor.decrypt(offset, &mut buf[0..read_size]);
-        output_file.write_all(&buf[0..read_size]).unwrap();
-        eprint!(".");
-
-        // Keep track of the progress.
-        bytes_to_decrypt -= read_size;
-        offset += read_size;
+#[cfg(target_os = "linux")]
+fn watch_command(args: &[String]) -> Result<(), Box<dyn Error>> {
+    use std::path::PathBuf;
+    use std::time::Duration;
+    use watch::{AfterDecrypt, WatchOptions};
+
+    let mut input_dir = None;
+    let mut output_dir = None;
+    let mut settle_time = Duration::from_secs(3);
+    let mut after = AfterDecrypt::Keep;
+
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--out" => {
+                let dir = args
+                    .next()
+                    .ok_or_else(|| usage_error("--out needs a directory"))?;
+                output_dir = Some(PathBuf::from(dir));
+            }
+            "--settle" => {
+                let secs = args
+                    .next()
+                    .ok_or_else(|| usage_error("--settle needs a number"))?;
+                settle_time = secs
+                    .parse::<f64>()
+                    .ok()
+                    .and_then(|secs| Duration::try_from_secs_f64(secs).ok())
+                    .ok_or_else(|| usage_error("invalid --settle value"))?;
+            }
+            "--delete" => after = AfterDecrypt::Delete,
+            "--move-to" => {
+                let dir = args
+                    .next()
+                    .ok_or_else(|| usage_error("--move-to needs a directory"))?;
+                after = AfterDecrypt::MoveTo(PathBuf::from(dir));
+            }
+            _ if input_dir.is_none() => input_dir = Some(PathBuf::from(arg)),
+            _ => return Err(usage_error(&format!("unexpected argument '{}'", arg))),
+        }
     }
 
-    input_file.try_clone().unwrap();
-    output_file.try_clone().unwrap();
+    watch::watch(&WatchOptions {
+        input_dir: input_dir.ok_or_else(|| usage_error("missing directory to watch"))?,
+        output_dir: output_dir.ok_or_else(|| usage_error("missing --out directory"))?,
+        settle_time,
+        after,
+    })
+}
 
-    eprintln!("done!");
+#[cfg(not(target_os = "linux"))]
+fn watch_command(_args: &[String]) -> Result<(), Box<dyn Error>> {
+    Err("watch mode is only available on Linux".into())
 }
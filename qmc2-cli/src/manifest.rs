@@ -0,0 +1,127 @@
+use crate::output::AtomicOutput;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::collections::BTreeMap;
+use std::error::Error;
+use std::fs::{self, File};
+use std::io::{self, ErrorKind, Write};
+use std::path::Path;
+use std::time::UNIX_EPOCH;
+
+/// Name of the manifest file, kept in the root of the output directory.
+pub const MANIFEST_NAME: &str = ".qmc2-manifest.json";
+
+const MANIFEST_VERSION: u32 = 1;
+
+/// What a previous batch run did, so unchanged inputs can be skipped.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Manifest {
+    version: u32,
+    /// Keyed by the input path, relative to the input root.
+    pub entries: BTreeMap<String, Entry>,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Entry {
+    pub size: u64,
+    pub mtime_ns: u64,
+    pub song_id: String,
+    pub sha256: String,
+    /// Relative to the output root.
+    pub output: String,
+}
+
+impl Manifest {
+    /// Load the manifest from `output_dir`, or start a new one.
+    pub fn load(output_dir: &Path) -> Result<Self, Box<dyn Error>> {
+        let data = match fs::read(output_dir.join(MANIFEST_NAME)) {
+            Ok(data) => data,
+            Err(err) if err.kind() == ErrorKind::NotFound => return Ok(Manifest::new()),
+            Err(err) => return Err(err.into()),
+        };
+
+        let manifest: Manifest = serde_json::from_slice(&data)?;
+        if manifest.version != MANIFEST_VERSION {
+            return Err(format!("unsupported manifest version {}", manifest.version).into());
+        }
+        Ok(manifest)
+    }
+
+    pub fn new() -> Self {
+        Manifest {
+            version: MANIFEST_VERSION,
+            entries: BTreeMap::new(),
+        }
+    }
+
+    pub fn save(&self, output_dir: &Path) -> Result<(), Box<dyn Error>> {
+        let mut output = AtomicOutput::open(&output_dir.join(MANIFEST_NAME), false)?;
+        serde_json::to_writer_pretty(&mut output, self)?;
+        output.write_all(b"\n")?;
+        output.commit()?;
+        Ok(())
+    }
+}
+
+/// Size and modification time, the cheap part of telling whether a file changed.
+pub fn file_stamp(path: &Path) -> io::Result<(u64, u64)> {
+    let meta = fs::metadata(path)?;
+    let mtime_ns = meta
+        .modified()?
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_nanos() as u64)
+        .unwrap_or_default();
+    Ok((meta.len(), mtime_ns))
+}
+
+pub fn hash_file(path: &Path) -> io::Result<String> {
+    let mut hasher = Sha256::new();
+    io::copy(&mut File::open(path)?, &mut hasher)?;
+    Ok(hasher
+        .finalize()
+        .iter()
+        .map(|b| format!("{:02x}", b))
+        .collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_manifest_round_trip() {
+        let dir = std::env::temp_dir().join(format!("qmc2-manifest-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+
+        assert!(Manifest::load(&dir).unwrap().entries.is_empty());
+
+        let entry = Entry {
+            size: 42,
+            mtime_ns: 1_600_000_000_000_000_000,
+            song_id: "12345".into(),
+            sha256: "00".repeat(32),
+            output: "album/song.flac".into(),
+        };
+        let mut manifest = Manifest::new();
+        manifest
+            .entries
+            .insert("album/song.mflac".into(), entry.clone());
+        manifest.save(&dir).unwrap();
+
+        let loaded = Manifest::load(&dir).unwrap();
+        assert_eq!(loaded.entries.get("album/song.mflac"), Some(&entry));
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_hash_file() {
+        let path = std::env::temp_dir().join(format!("qmc2-hash-{}", std::process::id()));
+        fs::write(&path, b"abc").unwrap();
+        assert_eq!(
+            hash_file(&path).unwrap(),
+            "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad"
+        );
+        fs::remove_file(&path).unwrap();
+    }
+}
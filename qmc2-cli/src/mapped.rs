@@ -0,0 +1,352 @@
+use crate::decrypt::{extension_of, registry};
+use crate::progress::ProgressBar;
+use memmap2::{Mmap, MmapMut};
+use qmc2_crypto::errors::DecryptError;
+use qmc2_crypto::format::{Cipher, FileReport, Format, Opened};
+use qmc2_crypto::sniff::{AudioFormat, SNIFF_SIZE};
+use qmc2_crypto::stream::Progress;
+use qmc2_crypto::{BlockCrypto, QMC2Crypto};
+use std::error::Error;
+use std::ffi::OsString;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Cursor, Seek, SeekFrom, Write};
+use std::ops::Range;
+use std::path::{Path, PathBuf};
+use std::thread;
+
+/// Bytes handed to the threads at a time; progress is reported in between.
+const WINDOW_SIZE: usize = 32 * 1024 * 1024;
+
+/// Below this, another thread costs more than it saves.
+const MIN_CHUNK_SIZE: usize = 1024 * 1024;
+
+/// Decrypt the file mapped at `input` into `writer`.
+///
+/// Reading goes through the page cache instead of `read` calls, and each
+/// window of the file is decrypted on every core before being written.
+pub fn decrypt_mapped<W: Write>(
+    input: &File,
+    ext: &str,
+    writer: &mut W,
+    progress: &mut ProgressBar,
+) -> Result<FileReport, Box<dyn Error>> {
+    // Safety: as with any mapping, the file must not shrink while we read it.
+    let map = unsafe { Mmap::map(input)? };
+    let (format, opened) = registry().open(&mut Cursor::new(&map[..]), ext)?;
+    let audio = &map[opened.audio_range.start as usize..opened.audio_range.end as usize];
+    let window_size = window_size(&opened.cipher);
+
+    let mut buf = vec![0u8; std::cmp::min(audio.len(), window_size)];
+    let mut head = vec![];
+    let mut position = 0;
+    let total = opened.audio_len;
+    progress.update(Progress { position, total });
+    for (i, window) in audio.chunks(window_size).enumerate() {
+        let buf = &mut buf[..window.len()];
+        buf.copy_from_slice(window);
+        for part in decrypt_window(&opened.cipher, (i * window_size) as u64, buf)? {
+            let plain = &buf[part];
+            let head_left = SNIFF_SIZE.saturating_sub(head.len());
+            head.extend_from_slice(&plain[..std::cmp::min(plain.len(), head_left)]);
+            writer.write_all(plain)?;
+            position += plain.len() as u64;
+        }
+        progress.update(Progress { position, total });
+    }
+
+    Ok(report(format, opened, &head))
+}
+
+/// Decrypt the file at `path` over itself, then cut off everything that is
+/// not audio.
+///
+/// An interrupted run leaves the file half decrypted, which cannot be
+/// resumed. While it runs, `<path>.in-place` records how far it got, and
+/// later runs refuse to touch the file as long as that marker is there.
+pub fn decrypt_in_place(
+    path: &Path,
+    progress: &mut ProgressBar,
+) -> Result<FileReport, Box<dyn Error>> {
+    let marker_path = marker_path_for(path);
+    if marker_path.exists() {
+        return Err(format!(
+            "{} was left half decrypted by an interrupted run (see {})",
+            path.display(),
+            marker_path.display()
+        )
+        .into());
+    }
+
+    let file = OpenOptions::new().read(true).write(true).open(path)?;
+    // Safety: as with any mapping, the file must not shrink while we use it.
+    let mut map = unsafe { MmapMut::map_mut(&file)? };
+    let (format, opened) = registry().open(&mut Cursor::new(&map[..]), extension_of(path))?;
+    let range = opened.audio_range.start as usize..opened.audio_range.end as usize;
+    let window_size = window_size(&opened.cipher);
+    // Nothing is written before this: a wrong key must not cost the only copy.
+    check_blocks(&opened.cipher, &map[range.clone()], window_size)?;
+    let mut marker = File::create(&marker_path)?;
+
+    // Plain text is never longer than what it was decrypted from, so moving
+    // it to the front (past any header) never overwrites what is left to do.
+    let mut position = 0;
+    let total = opened.audio_len;
+    progress.update(Progress { position: 0, total });
+    for start in range.clone().step_by(window_size) {
+        let end = std::cmp::min(start + window_size, range.end);
+        let offset = (start - range.start) as u64;
+        write_marker(&mut marker, offset, total)?;
+        for part in decrypt_window(&opened.cipher, offset, &mut map[start..end])? {
+            let len = part.len();
+            map.copy_within(start + part.start..start + part.end, position);
+            position += len;
+        }
+        progress.update(Progress {
+            position: position as u64,
+            total,
+        });
+    }
+
+    let head = map[..std::cmp::min(position, SNIFF_SIZE)].to_vec();
+    let report = report(format, opened, &head);
+
+    map.flush()?;
+    drop(map);
+    file.set_len(position as u64)?;
+    file.sync_all()?;
+    drop(marker);
+    fs::remove_file(&marker_path)?;
+    Ok(report)
+}
+
+/// Decrypt every block of `audio` into a scratch buffer, to fail on a wrong
+/// key before anything is overwritten.
+///
+/// Block ciphers only find a wrong key through the padding, and a wrong key
+/// can still give valid padding in the first blocks. Stream ciphers have
+/// nothing to check.
+fn check_blocks(cipher: &Cipher, audio: &[u8], window_size: usize) -> Result<(), DecryptError> {
+    if let Cipher::Stream(_) = cipher {
+        return Ok(());
+    }
+    let mut buf = vec![0u8; std::cmp::min(audio.len(), window_size)];
+    for (i, window) in audio.chunks(window_size).enumerate() {
+        let buf = &mut buf[..window.len()];
+        buf.copy_from_slice(window);
+        decrypt_window(cipher, (i * window_size) as u64, buf)?;
+    }
+    Ok(())
+}
+
+fn marker_path_for(path: &Path) -> PathBuf {
+    let mut name = path.file_name().map(OsString::from).unwrap_or_default();
+    name.push(".in-place");
+    path.with_file_name(name)
+}
+
+/// Replace the marker's contents with the window about to be decrypted.
+fn write_marker(marker: &mut File, offset: u64, total: u64) -> io::Result<()> {
+    marker.set_len(0)?;
+    marker.seek(SeekFrom::Start(0))?;
+    writeln!(
+        marker,
+        "decrypting in place, from byte {} of {} bytes of audio",
+        offset, total
+    )
+}
+
+/// How much encrypted data to decrypt at a time: whole blocks, for block ciphers.
+fn window_size(cipher: &Cipher) -> usize {
+    match cipher {
+        Cipher::Stream(_) => WINDOW_SIZE,
+        Cipher::Block(crypto) => {
+            let block_size = crypto.encrypted_block_size();
+            (WINDOW_SIZE / block_size).max(1) * block_size
+        }
+    }
+}
+
+/// Decrypt a window of encrypted audio, `offset` bytes into it, in place.
+///
+/// Returns where the plain text ended up in `window`, in order.
+fn decrypt_window(
+    cipher: &Cipher,
+    offset: u64,
+    window: &mut [u8],
+) -> Result<Vec<Range<usize>>, DecryptError> {
+    match cipher {
+        Cipher::Stream(crypto) => {
+            decrypt_parallel(&**crypto, offset, window);
+            let whole = 0..window.len();
+            Ok(vec![whole])
+        }
+        Cipher::Block(crypto) => {
+            let block_size = crypto.encrypted_block_size();
+            let lens = decrypt_blocks_parallel(&**crypto, window)?;
+            Ok(lens
+                .into_iter()
+                .enumerate()
+                .map(|(i, len)| i * block_size..i * block_size + len)
+                .collect())
+        }
+    }
+}
+
+/// Every cipher can start at any offset, so each thread simply takes a slice.
+fn decrypt_parallel(crypto: &dyn QMC2Crypto, offset: u64, buf: &mut [u8]) {
+    let threads = thread::available_parallelism().map_or(1, |n| n.get());
+    let chunk_size = buf.len().div_ceil(threads).max(MIN_CHUNK_SIZE);
+
+    thread::scope(|scope| {
+        for (i, chunk) in buf.chunks_mut(chunk_size).enumerate() {
+            let chunk_offset = offset + (i * chunk_size) as u64;
+            scope.spawn(move || crypto.decrypt(chunk_offset, chunk));
+        }
+    });
+}
+
+/// Blocks are independent, so each thread takes a run of them.
+///
+/// Returns the length of the plain text of each block.
+fn decrypt_blocks_parallel(
+    crypto: &dyn BlockCrypto,
+    buf: &mut [u8],
+) -> Result<Vec<usize>, DecryptError> {
+    let threads = thread::available_parallelism().map_or(1, |n| n.get());
+    let block_size = crypto.encrypted_block_size();
+    let blocks_per_thread = buf.len().div_ceil(block_size).div_ceil(threads).max(1);
+
+    thread::scope(|scope| {
+        let runs: Vec<_> = buf
+            .chunks_mut(blocks_per_thread * block_size)
+            .map(|run| {
+                scope.spawn(move || {
+                    run.chunks_mut(block_size)
+                        .map(|block| crypto.decrypt_block(block))
+                        .collect::<Result<Vec<_>, _>>()
+                })
+            })
+            .collect();
+
+        let mut lens = vec![];
+        for run in runs {
+            lens.extend(run.join().expect("decrypting thread panicked")?);
+        }
+        Ok(lens)
+    })
+}
+
+/// `head` is the start of the decrypted audio.
+fn report(format: &dyn Format, opened: Opened, head: &[u8]) -> FileReport {
+    FileReport {
+        container: format.name(),
+        song_id: opened.song_id,
+        metadata: opened.metadata,
+        format: AudioFormat::sniff(head),
+        audio_len: opened.audio_len,
+        bytes_written: opened.audio_len,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use qmc2_crypto::crypto_from_key;
+    use qmc2_crypto::joox::{encrypt_v4, JooxCrypto};
+    use qmc2_crypto::test_util::{qtag_file, test_audio, test_key};
+
+    fn temp_path(name: &str) -> PathBuf {
+        std::env::temp_dir().join(format!("qmc2-mapped-{}-{}", std::process::id(), name))
+    }
+
+    #[test]
+    fn test_decrypt_parallel() {
+        let key: Vec<u8> = (0..256).map(|i| (i * 3 + 1) as u8).collect();
+        let crypto = crypto_from_key(&key).unwrap();
+        let mut expected = vec![0u8; 5 * MIN_CHUNK_SIZE + 123];
+        let mut buf = expected.clone();
+
+        crypto.decrypt(7, &mut expected);
+        decrypt_parallel(&*crypto, 7, &mut buf);
+        assert_eq!(buf, expected);
+    }
+
+    #[test]
+    fn test_decrypt_window_blocks() {
+        let uuid = "ffffffffffffffffffffffffffffffff";
+        let audio = test_audio(b"fLaC", 2 * 1024 * 1024 + 100);
+        let file = encrypt_v4(uuid, &audio);
+        let cipher = Cipher::Block(Box::new(JooxCrypto::new(uuid)));
+
+        let mut window = file[12..].to_vec();
+        let mut output = vec![];
+        for part in decrypt_window(&cipher, 0, &mut window).unwrap() {
+            output.extend_from_slice(&window[part]);
+        }
+        assert_eq!(output, audio);
+    }
+
+    #[test]
+    fn test_check_blocks() {
+        let uuid = "ffffffffffffffffffffffffffffffff";
+        let mut file = encrypt_v4(uuid, &test_audio(b"fLaC", 2 * 1024 * 1024 + 100));
+        let cipher = Cipher::Block(Box::new(JooxCrypto::new(uuid)));
+        let window_size = window_size(&cipher);
+        assert!(check_blocks(&cipher, &file[12..], window_size).is_ok());
+
+        // Only the last block is bad, as a wrong key could leave it.
+        let len = file.len();
+        file[len - 1] ^= 0xff;
+        let err = check_blocks(&cipher, &file[12..], window_size).unwrap_err();
+        assert_eq!(err.code(), "INVALID_PADDING");
+    }
+
+    #[test]
+    fn test_decrypt_mapped() {
+        let audio = test_audio(b"fLaC", 3 * MIN_CHUNK_SIZE + 17);
+        let path = temp_path("read.mflac");
+        fs::write(&path, qtag_file(&audio, &test_key(512))).unwrap();
+
+        let mut output = vec![];
+        let file = File::open(&path).unwrap();
+        let report = decrypt_mapped(&file, "mflac", &mut output, &mut ProgressBar::new()).unwrap();
+        fs::remove_file(&path).unwrap();
+
+        assert_eq!(output, audio);
+        assert_eq!(report.format, AudioFormat::Flac);
+        assert_eq!(report.container, "qmc2-qtag");
+        assert_eq!(report.song_id, "12345");
+    }
+
+    #[test]
+    fn test_decrypt_in_place() {
+        let audio = test_audio(b"fLaC", 3 * MIN_CHUNK_SIZE + 17);
+        let path = temp_path("in-place.mflac");
+        fs::write(&path, qtag_file(&audio, &test_key(512))).unwrap();
+
+        let report = decrypt_in_place(&path, &mut ProgressBar::new()).unwrap();
+        let output = fs::read(&path).unwrap();
+        fs::remove_file(&path).unwrap();
+
+        assert_eq!(output, audio);
+        assert_eq!(report.audio_len, audio.len() as u64);
+        assert_eq!(report.format, AudioFormat::Flac);
+        assert!(!marker_path_for(&path).exists());
+    }
+
+    #[test]
+    fn test_in_place_refuses_interrupted_file() {
+        let path = temp_path("interrupted.mflac");
+        let encrypted = qtag_file(&test_audio(b"fLaC", 1000), &test_key(512));
+        fs::write(&path, &encrypted).unwrap();
+        fs::write(marker_path_for(&path), "").unwrap();
+
+        let result = decrypt_in_place(&path, &mut ProgressBar::new());
+        let output = fs::read(&path).unwrap();
+        fs::remove_file(&path).unwrap();
+        fs::remove_file(marker_path_for(&path)).unwrap();
+
+        assert!(result.is_err());
+        assert_eq!(output, encrypted);
+    }
+}
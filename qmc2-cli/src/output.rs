@@ -0,0 +1,120 @@
+use std::ffi::OsString;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+
+/// An output file that only shows up at its final path once fully written.
+///
+/// Data is written to `<output>.part` in the same directory. `commit` fsyncs
+/// it and renames it over the target, so an interrupted run never leaves a
+/// truncated file that looks complete.
+pub struct AtomicOutput {
+    file: File,
+    part_path: PathBuf,
+    target_path: PathBuf,
+}
+
+impl AtomicOutput {
+    /// Open the `.part` file for `path`.
+    ///
+    /// When `resume` is set, an existing partial file is kept and writing
+    /// continues from its end; otherwise it is truncated.
+    pub fn open(path: &Path, resume: bool) -> io::Result<Self> {
+        let part_path = part_path_for(path);
+        let mut file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(!resume)
+            .open(&part_path)?;
+        file.seek(SeekFrom::End(0))?;
+
+        Ok(AtomicOutput {
+            file,
+            part_path,
+            target_path: path.to_path_buf(),
+        })
+    }
+
+    /// Number of bytes already in the partial file.
+    pub fn len(&self) -> io::Result<u64> {
+        Ok(self.file.metadata()?.len())
+    }
+
+    /// Drop everything after the first `len` bytes and continue writing from there.
+    pub fn truncate(&mut self, len: u64) -> io::Result<()> {
+        self.file.set_len(len)?;
+        self.file.seek(SeekFrom::Start(len))?;
+        Ok(())
+    }
+
+    /// Read back previously written data, then return to the end of the file.
+    pub fn read_back(&mut self, offset: u64, buf: &mut [u8]) -> io::Result<()> {
+        self.file.seek(SeekFrom::Start(offset))?;
+        self.file.read_exact(buf)?;
+        self.file.seek(SeekFrom::End(0))?;
+        Ok(())
+    }
+
+    /// Flush the data to disk and move it to its final path.
+    pub fn commit(self) -> io::Result<()> {
+        self.file.sync_all()?;
+        drop(self.file);
+
+        fs::rename(&self.part_path, &self.target_path)?;
+        sync_parent_dir(&self.target_path)
+    }
+}
+
+impl Write for AtomicOutput {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.file.write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.file.flush()
+    }
+}
+
+/// Remove an output that is no longer wanted; one already gone is fine.
+pub fn remove_output(path: &Path) -> io::Result<()> {
+    match fs::remove_file(path) {
+        Err(err) if err.kind() != io::ErrorKind::NotFound => Err(err),
+        _ => Ok(()),
+    }
+}
+
+fn part_path_for(path: &Path) -> PathBuf {
+    let mut name = path.file_name().map(OsString::from).unwrap_or_default();
+    name.push(".part");
+    path.with_file_name(name)
+}
+
+/// Make the rename itself durable.
+#[cfg(unix)]
+fn sync_parent_dir(path: &Path) -> io::Result<()> {
+    let dir = match path.parent() {
+        Some(dir) if !dir.as_os_str().is_empty() => dir,
+        _ => Path::new("."),
+    };
+    File::open(dir)?.sync_all()
+}
+
+#[cfg(not(unix))]
+fn sync_parent_dir(_path: &Path) -> io::Result<()> {
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_part_path() {
+        assert_eq!(
+            part_path_for(Path::new("out/song.flac")),
+            Path::new("out/song.flac.part")
+        );
+        assert_eq!(part_path_for(Path::new("a.ogg")), Path::new("a.ogg.part"));
+    }
+}
@@ -0,0 +1,211 @@
+use qmc2_crypto::stream::Progress;
+use std::io::{self, IsTerminal, Write};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::{Duration, Instant};
+
+/// Redraw at most this often; drawing every block would slow down small blocks.
+const REDRAW_INTERVAL: Duration = Duration::from_millis(100);
+
+static QUIET: AtomicBool = AtomicBool::new(false);
+
+pub fn set_quiet(quiet: bool) {
+    QUIET.store(quiet, Ordering::Relaxed);
+}
+
+pub fn is_quiet() -> bool {
+    QUIET.load(Ordering::Relaxed)
+}
+
+/// `eprintln!`, unless `--quiet` was given.
+macro_rules! info {
+    ($($arg:tt)*) => {
+        if !$crate::progress::is_quiet() {
+            eprintln!($($arg)*);
+        }
+    };
+}
+pub(crate) use info;
+
+/// Progress line on stderr, for a single file or a whole batch.
+///
+/// Only drawn when stderr is a terminal and `--quiet` was not given.
+pub struct ProgressBar {
+    enabled: bool,
+    started: Instant,
+    last_draw: Option<Instant>,
+    files_total: usize,
+    files_done: usize,
+    /// Sum of the audio sizes of every file; `0` when only the current file counts.
+    bytes_total: u64,
+    /// Bytes of finished (or skipped) files.
+    bytes_done: u64,
+    /// Bytes actually decrypted during this run, for the speed.
+    bytes_processed: u64,
+    current: Option<Progress>,
+    /// Where the current file started, which is not `0` when resuming.
+    current_start: u64,
+}
+
+impl ProgressBar {
+    /// Progress for a single file.
+    pub fn new() -> Self {
+        ProgressBar::for_batch(1, 0)
+    }
+
+    /// Progress across `files_total` files, `bytes_total` bytes of audio in total.
+    pub fn for_batch(files_total: usize, bytes_total: u64) -> Self {
+        ProgressBar {
+            enabled: ProgressBar::is_drawn(),
+            started: Instant::now(),
+            last_draw: None,
+            files_total,
+            files_done: 0,
+            bytes_total,
+            bytes_done: 0,
+            bytes_processed: 0,
+            current: None,
+            current_start: 0,
+        }
+    }
+
+    /// Whether progress bars are drawn at all, so callers can skip working out
+    /// totals nobody will see.
+    pub fn is_drawn() -> bool {
+        !is_quiet() && io::stderr().is_terminal()
+    }
+
+    /// Called with every progress report of the current file.
+    pub fn update(&mut self, progress: Progress) {
+        if self.current.is_none() {
+            self.current_start = progress.position;
+        }
+        self.current = Some(progress);
+
+        let now = Instant::now();
+        let due = self
+            .last_draw
+            .is_none_or(|last| now.duration_since(last) >= REDRAW_INTERVAL);
+        if self.enabled && (due || progress.position == progress.total) {
+            self.last_draw = Some(now);
+            self.draw(now);
+        }
+    }
+
+    /// The current file is done; `audio_len` is what it counts for in the batch total.
+    pub fn finish_file(&mut self, audio_len: u64) {
+        self.files_done += 1;
+        self.bytes_done += audio_len;
+        if let Some(progress) = self.current.take() {
+            self.bytes_processed += progress.position - self.current_start;
+        }
+        self.clear();
+    }
+
+    /// A file was skipped without being decrypted.
+    pub fn skip_file(&mut self, audio_len: u64) {
+        self.files_done += 1;
+        self.bytes_done += audio_len;
+    }
+
+    /// Remove the progress line, so normal messages can be printed.
+    pub fn clear(&mut self) {
+        if self.enabled && self.last_draw.is_some() {
+            eprint!("\r\x1b[K");
+            self.last_draw = None;
+        }
+    }
+
+    fn draw(&self, now: Instant) {
+        let current = self.current.unwrap_or(Progress {
+            position: self.current_start,
+            total: 0,
+        });
+
+        let (done, total) = if self.bytes_total > 0 {
+            (self.bytes_done + current.position, self.bytes_total)
+        } else {
+            (current.position, current.total)
+        };
+
+        let elapsed = now.duration_since(self.started).as_secs_f64();
+        let processed = self.bytes_processed + current.position - self.current_start;
+        let speed = if elapsed > 0.0 {
+            processed as f64 / elapsed
+        } else {
+            0.0
+        };
+
+        let mut line = String::new();
+        if self.files_total > 1 {
+            line += &format!("[{}/{}] ", self.files_done + 1, self.files_total);
+        }
+        line += &format!(
+            "{:5.1}%  {} / {}  {}/s  ETA {}",
+            percentage(done, total),
+            format_bytes(done as f64),
+            format_bytes(total as f64),
+            format_bytes(speed),
+            format_eta(total.saturating_sub(done), speed),
+        );
+
+        let mut stderr = io::stderr().lock();
+        let _ = write!(stderr, "\r\x1b[K{}", line);
+        let _ = stderr.flush();
+    }
+}
+
+fn percentage(done: u64, total: u64) -> f64 {
+    if total == 0 {
+        100.0
+    } else {
+        done as f64 * 100.0 / total as f64
+    }
+}
+
+fn format_bytes(bytes: f64) -> String {
+    const UNITS: [&str; 4] = ["B", "KiB", "MiB", "GiB"];
+    let mut value = bytes;
+    let mut unit = 0;
+    while value >= 1024.0 && unit < UNITS.len() - 1 {
+        value /= 1024.0;
+        unit += 1;
+    }
+
+    if unit == 0 {
+        format!("{} {}", value as u64, UNITS[unit])
+    } else {
+        format!("{:.1} {}", value, UNITS[unit])
+    }
+}
+
+fn format_eta(bytes_left: u64, speed: f64) -> String {
+    if speed <= 0.0 {
+        return "--:--".into();
+    }
+
+    let secs = (bytes_left as f64 / speed).ceil() as u64;
+    if secs >= 3600 {
+        format!("{}:{:02}:{:02}", secs / 3600, secs / 60 % 60, secs % 60)
+    } else {
+        format!("{:02}:{:02}", secs / 60, secs % 60)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_format_bytes() {
+        assert_eq!(format_bytes(512.0), "512 B");
+        assert_eq!(format_bytes(1536.0), "1.5 KiB");
+        assert_eq!(format_bytes(200.0 * 1024.0 * 1024.0), "200.0 MiB");
+    }
+
+    #[test]
+    fn test_format_eta() {
+        assert_eq!(format_eta(100, 0.0), "--:--");
+        assert_eq!(format_eta(150, 1.0), "02:30");
+        assert_eq!(format_eta(3661, 1.0), "1:01:01");
+    }
+}
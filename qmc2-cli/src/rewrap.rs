@@ -0,0 +1,65 @@
+use crate::output::AtomicOutput;
+use qmc2_crypto::{rewrap_stream, RewrapOptions, RewrapReport};
+use std::error::Error;
+use std::fs::File;
+use std::io::BufReader;
+use std::path::Path;
+
+/// Length of keys made by `--rekey`, the size current clients use (RC4).
+pub const DEFAULT_KEY_LEN: usize = 512;
+
+/// Copy `input_path` to `output_path` with a new trailer (and key, if given).
+pub fn rewrap_file(
+    input_path: &Path,
+    output_path: &Path,
+    options: &RewrapOptions,
+) -> Result<RewrapReport, Box<dyn Error>> {
+    let mut input = BufReader::new(File::open(input_path)?);
+    let mut output = AtomicOutput::open(output_path, false)?;
+    let report = rewrap_stream(&mut input, &mut output, options)?;
+    output.commit()?;
+    Ok(report)
+}
+
+/// A fresh random key of `len` bytes.
+pub fn generate_key(len: usize) -> Result<Vec<u8>, Box<dyn Error>> {
+    let mut key = vec![0u8; len];
+    getrandom::getrandom(&mut key)?;
+    Ok(key)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use qmc2_crypto::test_util::{qtag_file, test_audio, test_key};
+    use qmc2_crypto::trailer::TrailerKind;
+    use qmc2_crypto::{CipherKind, QMC2Reader};
+    use std::fs;
+    use std::io::Read;
+
+    #[test]
+    fn test_rewrap_file() {
+        let dir = std::env::temp_dir();
+        let input_path = dir.join(format!("qmc2-rewrap-{}.mflac", std::process::id()));
+        let output_path = dir.join(format!("qmc2-rewrap-{}.mgg", std::process::id()));
+
+        let audio = test_audio(b"fLaC", 20000);
+        fs::write(&input_path, qtag_file(&audio, &test_key(512))).unwrap();
+
+        let new_key = generate_key(256).unwrap();
+        let options = RewrapOptions {
+            new_key: Some(&new_key),
+            trailer: TrailerKind::V1,
+            ..Default::default()
+        };
+        let report = rewrap_file(&input_path, &output_path, &options).unwrap();
+        assert_eq!(report.cipher, CipherKind::Map);
+
+        let mut reader = QMC2Reader::new(File::open(&output_path).unwrap()).unwrap();
+        let mut decrypted = vec![];
+        reader.read_to_end(&mut decrypted).unwrap();
+        fs::remove_file(&input_path).unwrap();
+        fs::remove_file(&output_path).unwrap();
+        assert_eq!(decrypted, audio);
+    }
+}
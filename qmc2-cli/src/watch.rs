@@ -0,0 +1,280 @@
+use crate::decrypt::{decrypt_file, output_extension, output_path_in};
+use crate::output::remove_output;
+use crate::progress::{info, ProgressBar};
+use inotify::{Inotify, WatchMask};
+use std::collections::{HashMap, HashSet};
+use std::error::Error;
+use std::fs;
+use std::io::ErrorKind;
+use std::os::unix::fs::MetadataExt;
+use std::path::{Path, PathBuf};
+use std::thread;
+use std::time::{Duration, Instant};
+
+const POLL_INTERVAL: Duration = Duration::from_millis(250);
+
+pub enum AfterDecrypt {
+    Keep,
+    Delete,
+    MoveTo(PathBuf),
+}
+
+pub struct WatchOptions {
+    pub input_dir: PathBuf,
+    pub output_dir: PathBuf,
+    /// How long a file's size has to stay the same before it counts as complete.
+    pub settle_time: Duration,
+    pub after: AfterDecrypt,
+}
+
+/// Identifies a file's content regardless of its name, so a processed file
+/// that gets renamed (e.g. by the downloading client) is not picked up again.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+struct FileId {
+    dev: u64,
+    ino: u64,
+    size: u64,
+    mtime: i64,
+}
+
+impl FileId {
+    fn of(meta: &fs::Metadata) -> Self {
+        FileId {
+            dev: meta.dev(),
+            ino: meta.ino(),
+            size: meta.len(),
+            mtime: meta.mtime(),
+        }
+    }
+}
+
+struct Candidate {
+    size: Option<u64>,
+    stable_since: Instant,
+}
+
+#[derive(Default)]
+struct Tracker {
+    pending: HashMap<PathBuf, Candidate>,
+    processed: HashSet<FileId>,
+    /// Where each input was last decrypted to.
+    outputs: HashMap<PathBuf, PathBuf>,
+}
+
+impl Tracker {
+    /// Something happened to `path`; wait for it to settle (again).
+    fn touch(&mut self, path: PathBuf, now: Instant) {
+        self.pending.insert(
+            path,
+            Candidate {
+                size: None,
+                stable_since: now,
+            },
+        );
+    }
+
+    /// Take the files whose size has not changed for `settle_time` and that
+    /// have not already been processed under another name.
+    fn take_ready(&mut self, now: Instant, settle_time: Duration) -> Vec<(PathBuf, FileId)> {
+        let mut ready = vec![];
+
+        self.pending.retain(|path, candidate| {
+            let meta = match fs::metadata(path) {
+                Ok(meta) if meta.is_file() => meta,
+                // Gone (or replaced by something odd), forget about it.
+                _ => return false,
+            };
+
+            if candidate.size != Some(meta.len()) || meta.len() == 0 {
+                candidate.size = Some(meta.len());
+                candidate.stable_since = now;
+                return true;
+            }
+
+            if now.duration_since(candidate.stable_since) < settle_time {
+                return true;
+            }
+
+            ready.push((path.clone(), FileId::of(&meta)));
+            false
+        });
+
+        ready.retain(|(_, id)| !self.processed.contains(id));
+        ready
+    }
+
+    fn mark_processed(&mut self, id: FileId) {
+        self.processed.insert(id);
+    }
+
+    /// The other input already decrypted to `output`, if any; two inputs named
+    /// alike (e.g. `a.mflac` and `a.mflac0`) must not overwrite each other.
+    fn output_owner(&self, input: &Path, output: &Path) -> Option<&Path> {
+        self.outputs
+            .iter()
+            .find(|(other, other_output)| *other != input && *other_output == output)
+            .map(|(other, _)| other.as_path())
+    }
+
+    /// Record that `input` was decrypted to `output`; returns where it went
+    /// before, if that was elsewhere.
+    fn set_output(&mut self, input: PathBuf, output: PathBuf) -> Option<PathBuf> {
+        let previous = self.outputs.insert(input, output.clone());
+        previous.filter(|previous| *previous != output)
+    }
+}
+
+/// Decrypt files as they are completed in `options.input_dir`, until killed.
+pub fn watch(options: &WatchOptions) -> Result<(), Box<dyn Error>> {
+    fs::create_dir_all(&options.output_dir)?;
+    if let AfterDecrypt::MoveTo(dir) = &options.after {
+        fs::create_dir_all(dir)?;
+    }
+
+    let mut inotify = Inotify::init()?;
+    inotify.watches().add(
+        &options.input_dir,
+        WatchMask::CREATE | WatchMask::CLOSE_WRITE | WatchMask::MOVED_TO,
+    )?;
+    info!("Watching {}...", options.input_dir.display());
+
+    let mut tracker = Tracker::default();
+    let mut buffer = [0u8; 4096];
+    loop {
+        match inotify.read_events(&mut buffer) {
+            Ok(events) => {
+                let now = Instant::now();
+                for name in events.filter_map(|event| event.name) {
+                    let path = options.input_dir.join(name);
+                    if output_extension(&path).is_some() {
+                        tracker.touch(path, now);
+                    }
+                }
+            }
+            Err(err) if err.kind() == ErrorKind::WouldBlock => {}
+            Err(err) => return Err(err.into()),
+        }
+
+        for (path, id) in tracker.take_ready(Instant::now(), options.settle_time) {
+            match process(&path, options, &mut tracker) {
+                Ok(()) => tracker.mark_processed(id),
+                Err(err) => eprintln!("error: {}: {}", path.display(), err),
+            }
+        }
+
+        thread::sleep(POLL_INTERVAL);
+    }
+}
+
+fn process(
+    path: &Path,
+    options: &WatchOptions,
+    tracker: &mut Tracker,
+) -> Result<(), Box<dyn Error>> {
+    let output_path = output_path_in(path, &options.output_dir).ok_or("unsupported file")?;
+    if let Some(owner) = tracker.output_owner(path, &output_path) {
+        let owner = owner.display();
+        return Err(format!(
+            "{} is already the output of {}",
+            output_path.display(),
+            owner
+        )
+        .into());
+    }
+    info!("{} -> {}", path.display(), output_path.display());
+    let mut progress = ProgressBar::new();
+    let result = decrypt_file(path, &output_path, &Default::default(), &mut progress);
+    progress.clear();
+    result?;
+
+    // The output got another name, e.g. from the format in the metadata.
+    if let Some(previous) = tracker.set_output(path.to_path_buf(), output_path) {
+        info!("removing {}", previous.display());
+        remove_output(&previous)?;
+    }
+
+    match &options.after {
+        AfterDecrypt::Keep => {}
+        AfterDecrypt::Delete => fs::remove_file(path)?,
+        AfterDecrypt::MoveTo(dir) => {
+            let name = path.file_name().ok_or("invalid file name")?;
+            fs::rename(path, dir.join(name))?;
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn temp_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("qmc2-{}-{}", name, std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    #[test]
+    fn test_waits_for_size_to_settle() {
+        let dir = temp_dir("watch-settle");
+        let path = dir.join("a.mflac");
+        let settle = Duration::from_secs(2);
+        let start = Instant::now();
+
+        fs::write(&path, b"part").unwrap();
+        let mut tracker = Tracker::default();
+        tracker.touch(path.clone(), start);
+        assert!(tracker.take_ready(start, settle).is_empty());
+
+        fs::write(&path, b"part, then more").unwrap();
+        let later = start + settle;
+        assert!(tracker.take_ready(later, settle).is_empty());
+
+        let ready = tracker.take_ready(later + settle, settle);
+        assert_eq!(ready.len(), 1);
+        assert_eq!(ready[0].0, path);
+        assert!(tracker.take_ready(later + settle * 2, settle).is_empty());
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_skips_renamed_file() {
+        let dir = temp_dir("watch-rename");
+        let path = dir.join("a.mflac");
+        let renamed = dir.join("b.mflac");
+        let settle = Duration::from_secs(1);
+        let start = Instant::now();
+
+        fs::write(&path, b"encrypted").unwrap();
+        let mut tracker = Tracker::default();
+        tracker.touch(path.clone(), start);
+        tracker.take_ready(start, settle);
+        let ready = tracker.take_ready(start + settle, settle);
+        assert_eq!(ready.len(), 1);
+        tracker.mark_processed(ready[0].1);
+
+        fs::rename(&path, &renamed).unwrap();
+        tracker.touch(renamed, start);
+        tracker.take_ready(start, settle);
+        assert!(tracker.take_ready(start + settle, settle).is_empty());
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_tracks_outputs() {
+        let mut tracker = Tracker::default();
+        let (a, a0) = (PathBuf::from("in/a.mflac"), PathBuf::from("in/a.mflac0"));
+        let (flac, mp3) = (PathBuf::from("out/a.flac"), PathBuf::from("out/a.mp3"));
+
+        assert_eq!(tracker.set_output(a.clone(), flac.clone()), None);
+        assert_eq!(tracker.output_owner(&a, &flac), None);
+        assert_eq!(tracker.output_owner(&a0, &flac), Some(a.as_path()));
+
+        assert_eq!(tracker.set_output(a.clone(), flac.clone()), None);
+        assert_eq!(tracker.set_output(a.clone(), mp3), Some(flac.clone()));
+        assert_eq!(tracker.output_owner(&a0, &flac), None);
+    }
+}
@@ -0,0 +1,34 @@
+#!/usr/bin/env bash
+# Decrypt a generated fixture with the WASI build of qmc2-cli under wasmtime,
+# with only the fixture's directories preopened.
+#
+# Needs `rustup target add wasm32-wasip1` and wasmtime on the PATH.
+
+set -e
+cd "$(realpath "$(dirname "$0")")/.."
+
+cargo build -p qmc2-cli --release --target wasm32-wasip1
+WASM=target/wasm32-wasip1/release/qmc2-cli.wasm
+
+WORK="$(mktemp -d)"
+trap 'rm -rf "$WORK"' EXIT
+mkdir "$WORK/in" "$WORK/out"
+
+head -c 3000000 /dev/urandom > "$WORK/plain.bin"
+for KEY_LEN in 512 256; do
+  cargo run -q -p qmc2-crypto --features qmc2-crypto/test-support --example make_fixture -- \
+    "$WORK/plain.bin" "$WORK/in/test-$KEY_LEN.mflac" "$KEY_LEN"
+done
+
+for KEY_LEN in 512 256; do
+  wasmtime run --dir "$WORK/in::/in" --dir "$WORK/out::/out" "$WASM" \
+    "/in/test-$KEY_LEN.mflac" "/out/test-$KEY_LEN.flac"
+  cmp "$WORK/plain.bin" "$WORK/out/test-$KEY_LEN.flac"
+done
+
+# Same again through batch mode, which walks the preopened directory.
+rm "$WORK/out/"*
+wasmtime run --dir "$WORK/in::/in" --dir "$WORK/out::/out" "$WASM" batch /in /out
+cmp "$WORK/plain.bin" "$WORK/out/test-512.flac"
+
+echo "WASI build OK"
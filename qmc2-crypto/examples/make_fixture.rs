@@ -0,0 +1,41 @@
+//! Encrypt a plain audio file into a QMC2 (QTag) file, for testing.
+//!
+//! Usage: make_fixture <input> <output> [key length]
+//!
+//! The key is derived from its length rather than random, so the same input
+//! always gives the same fixture. Keys over 300 bytes use RC4 (the default,
+//! 512), shorter ones the map cipher.
+//!
+//! Needs the `test-support` feature:
+//! `cargo run -p qmc2-crypto --features qmc2-crypto/test-support --example make_fixture`.
+
+use qmc2_crypto::test_util::{qtag_file, test_key};
+use std::env;
+use std::fs;
+use std::process;
+
+fn main() {
+    let args: Vec<String> = env::args().collect();
+    if args.len() < 3 || args.len() > 4 {
+        eprintln!("usage: {} <input> <output> [key length]", args[0]);
+        process::exit(1);
+    }
+
+    let key_len = match args.get(3).map(|len| len.parse::<usize>()) {
+        None => 512,
+        Some(Ok(len)) if len >= 8 => len,
+        Some(_) => {
+            eprintln!("error: key length must be a number, at least 8");
+            process::exit(1);
+        }
+    };
+    let data = fs::read(&args[1]).unwrap_or_else(|err| {
+        eprintln!("error: {}: {}", args[1], err);
+        process::exit(1);
+    });
+
+    if let Err(err) = fs::write(&args[2], qtag_file(&data, &test_key(key_len))) {
+        eprintln!("error: {}: {}", args[2], err);
+        process::exit(1);
+    }
+}
@@ -0,0 +1,191 @@
+use std::io::{self, SeekFrom};
+use std::pin::Pin;
+use std::task::{ready, Context, Poll};
+
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncSeek, AsyncSeekExt, ReadBuf};
+
+use super::detection::{detect, Detection, RECOMMENDED_DETECTION_SIZE};
+use super::errors::DecryptError;
+use super::qmc2::decrypt_factory;
+use super::qmc2_base::QMC2Crypto;
+use super::stream::trailer_positions;
+
+/// The async counterpart of `QMC2Reader`: reads the decrypted audio of a
+/// QMC2 file from a tokio `AsyncRead + AsyncSeek`, such as a `tokio::fs::File`.
+///
+/// Positions are within the audio data; the trailer is not visible.
+pub struct AsyncQMC2Reader<R> {
+    inner: R,
+    crypto: Box<dyn QMC2Crypto>,
+    detection: Detection,
+    audio_len: u64,
+    position: u64,
+    /// Where the seek in progress on `inner` will leave us.
+    seek_to: Option<u64>,
+}
+
+impl<R: AsyncRead + AsyncSeek + Unpin> AsyncQMC2Reader<R> {
+    /// Read the trailer of `inner` and start at the beginning of the audio.
+    pub async fn new(inner: R) -> Result<Self, DecryptError> {
+        AsyncQMC2Reader::with_ekey(inner, None).await
+    }
+
+    /// Like `new`, but with `ekey` instead of the one embedded in the file.
+    pub async fn with_ekey(mut inner: R, ekey: Option<&str>) -> Result<Self, DecryptError> {
+        // The same steps as `read_trailer`, which cannot be shared as it blocks.
+        let file_len = inner.seek(SeekFrom::End(0)).await?;
+        let detection_len = std::cmp::min(file_len, RECOMMENDED_DETECTION_SIZE as u64);
+        let detection_start = file_len - detection_len;
+
+        let mut detection_buf = vec![0u8; detection_len as usize];
+        inner.seek(SeekFrom::Start(detection_start)).await?;
+        inner.read_exact(&mut detection_buf).await?;
+        let detection = detect(&detection_buf)?;
+        let (audio_len, ekey_start) = trailer_positions(detection_start, &detection)?;
+
+        let crypto = match ekey {
+            Some(ekey) => decrypt_factory(ekey)?,
+            None => {
+                let mut ekey_buf = vec![0u8; detection.ekey_len];
+                inner.seek(SeekFrom::Start(ekey_start)).await?;
+                inner.read_exact(&mut ekey_buf).await?;
+                let ekey = std::str::from_utf8(&ekey_buf).map_err(|_| DecryptError::EKeyNotUtf8)?;
+                decrypt_factory(ekey)?
+            }
+        };
+        inner.seek(SeekFrom::Start(0)).await?;
+
+        Ok(AsyncQMC2Reader {
+            inner,
+            crypto,
+            detection,
+            audio_len,
+            position: 0,
+            seek_to: None,
+        })
+    }
+
+    pub fn detection(&self) -> &Detection {
+        &self.detection
+    }
+
+    /// Size of the audio data, i.e. the file without its trailer.
+    pub fn audio_len(&self) -> u64 {
+        self.audio_len
+    }
+
+    pub fn into_inner(self) -> R {
+        self.inner
+    }
+}
+
+impl<R: AsyncRead + AsyncSeek + Unpin> AsyncRead for AsyncQMC2Reader<R> {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        let audio_left = this.audio_len.saturating_sub(this.position);
+        let len = std::cmp::min(buf.remaining() as u64, audio_left) as usize;
+        if len == 0 {
+            return Poll::Ready(Ok(()));
+        }
+
+        let mut audio_buf = ReadBuf::new(buf.initialize_unfilled_to(len));
+        ready!(Pin::new(&mut this.inner).poll_read(cx, &mut audio_buf))?;
+
+        let n = audio_buf.filled().len();
+        this.crypto.decrypt(this.position, audio_buf.filled_mut());
+        this.position += n as u64;
+        buf.advance(n);
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl<R: AsyncRead + AsyncSeek + Unpin> AsyncSeek for AsyncQMC2Reader<R> {
+    fn start_seek(self: Pin<&mut Self>, pos: SeekFrom) -> io::Result<()> {
+        let this = self.get_mut();
+        let position = match pos {
+            SeekFrom::Start(offset) => Some(offset),
+            SeekFrom::End(offset) => this.audio_len.checked_add_signed(offset),
+            SeekFrom::Current(offset) => this.position.checked_add_signed(offset),
+        }
+        .ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "invalid seek to a negative or overflowing position",
+            )
+        })?;
+
+        // Like a file, seeking past the end is allowed; reads there return nothing.
+        Pin::new(&mut this.inner).start_seek(SeekFrom::Start(position))?;
+        this.seek_to = Some(position);
+        Ok(())
+    }
+
+    fn poll_complete(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<u64>> {
+        let this = self.get_mut();
+        ready!(Pin::new(&mut this.inner).poll_complete(cx))?;
+        if let Some(position) = this.seek_to.take() {
+            this.position = position;
+        }
+        Poll::Ready(Ok(this.position))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::crypto::key_dec::generate_ekey;
+    use crate::crypto::test_util::{qtag_file, test_audio, test_key, SONG_ID};
+    use std::io::Cursor;
+
+    #[tokio::test]
+    async fn test_async_read_and_seek() {
+        let audio = test_audio(b"fLaC", 30000);
+        let file = Cursor::new(qtag_file(&audio, &test_key(512)));
+        let mut reader = AsyncQMC2Reader::new(file).await.unwrap();
+        assert_eq!(reader.audio_len(), 30000);
+        assert_eq!(reader.detection().song_id, SONG_ID);
+
+        let mut all = vec![];
+        reader.read_to_end(&mut all).await.unwrap();
+        assert_eq!(all, audio);
+
+        assert_eq!(reader.seek(SeekFrom::End(-100)).await.unwrap(), 29900);
+        let mut buf = vec![];
+        reader.read_to_end(&mut buf).await.unwrap();
+        assert_eq!(buf, &audio[29900..]);
+
+        reader.seek(SeekFrom::Start(5000)).await.unwrap();
+        reader.seek(SeekFrom::Current(-10)).await.unwrap();
+        let mut buf = [0u8; 20];
+        reader.read_exact(&mut buf).await.unwrap();
+        assert_eq!(buf, &audio[4990..5010]);
+
+        assert!(reader.seek(SeekFrom::Current(-10000)).await.is_err());
+        reader.seek(SeekFrom::Start(40000)).await.unwrap();
+        assert_eq!(reader.read(&mut buf).await.unwrap(), 0);
+    }
+
+    #[tokio::test]
+    async fn test_ekey_override() {
+        let audio = test_audio(b"fLaC", 3000);
+        let key = test_key(512);
+        let mut file = qtag_file(&audio, &key);
+        // Not UTF-8 any more, so only the ekey given can be used.
+        file[audio.len()] = 0xff;
+
+        let err = AsyncQMC2Reader::new(Cursor::new(file.clone())).await.err();
+        assert!(matches!(err, Some(DecryptError::EKeyNotUtf8)));
+
+        let ekey = generate_ekey(&key);
+        let mut reader = AsyncQMC2Reader::with_ekey(Cursor::new(file), Some(&ekey))
+            .await
+            .unwrap();
+        let mut all = vec![];
+        reader.read_to_end(&mut all).await.unwrap();
+        assert_eq!(all, audio);
+    }
+}
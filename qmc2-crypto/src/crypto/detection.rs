@@ -40,6 +40,9 @@ fn find_comma(buf: &[u8], start: usize, end: usize) -> Option<usize> {
 
 pub const RECOMMENDED_DETECTION_SIZE: usize = 0x40;
 
+/// Longest ekey a v1 trailer (which only stores its length) can be recognised with.
+pub const MAX_V1_EKEY_LEN: usize = 0x300;
+
 fn detect_v1(buf: &[u8]) -> Result<Detection, DetectionError> {
     // key size is always unsigned.
     let key_size = buf.read_u32_le(buf.len() - 4) as usize;
@@ -97,7 +100,7 @@ pub fn detect(buf: &[u8]) -> Result<Detection, DetectionError> {
     // QMC2 v1: eof_magic is actually a size.
     let len_v1 = eof_magic;
     // Known max size is 528 bytes (0x210), round it up.
-    if 0 < len_v1 && len_v1 <= 0x300 {
+    if 0 < len_v1 && len_v1 as usize <= MAX_V1_EKEY_LEN {
         return detect_v1(buf);
     }
 
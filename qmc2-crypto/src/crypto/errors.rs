@@ -4,6 +4,8 @@ use std::fmt;
 pub enum CryptoError {
     EKeyParseError,
     QMC2KeyDeriveError,
+    /// A block did not decrypt to valid padding; usually a wrong key.
+    InvalidPadding,
 }
 
 impl fmt::Display for CryptoError {
@@ -15,14 +17,35 @@ impl fmt::Display for CryptoError {
             CryptoError::QMC2KeyDeriveError => {
                 write!(f, "Failed to derive real QMC2 key")
             }
+            CryptoError::InvalidPadding => {
+                write!(
+                    f,
+                    "invalid padding after decryption, the key is probably wrong"
+                )
+            }
+        }
+    }
+}
+
+impl CryptoError {
+    /// Stable identifier for bindings, which should not match on messages.
+    pub fn code(&self) -> &'static str {
+        match self {
+            CryptoError::EKeyParseError => "EKEY_PARSE",
+            CryptoError::QMC2KeyDeriveError => "KEY_DERIVE",
+            CryptoError::InvalidPadding => "INVALID_PADDING",
         }
     }
 }
 
+impl std::error::Error for CryptoError {}
+
 #[derive(Clone, Debug, PartialEq, Eq)]
 pub enum DetectionError {
     BufferTooSmall,
     CouldNotIdentifyEndOfEKey,
+    /// The trailer points before the start of the file.
+    PositionOutOfRange,
     SongIdOverflow,
     ZerosAtEOF,
     UnknownMagicLE32(u32),
@@ -37,6 +60,9 @@ impl fmt::Display for DetectionError {
             DetectionError::CouldNotIdentifyEndOfEKey => {
                 write!(f, "Could not identify the end of EKey")
             }
+            DetectionError::PositionOutOfRange => {
+                write!(f, "trailer points outside the file")
+            }
             DetectionError::SongIdOverflow => {
                 write!(f, "Song ID too long")
             }
@@ -49,3 +75,125 @@ impl fmt::Display for DetectionError {
         }
     }
 }
+
+impl DetectionError {
+    /// Stable identifier for bindings, which should not match on messages.
+    pub fn code(&self) -> &'static str {
+        match self {
+            DetectionError::BufferTooSmall => "BUFFER_TOO_SMALL",
+            DetectionError::CouldNotIdentifyEndOfEKey => "EKEY_END_NOT_FOUND",
+            DetectionError::PositionOutOfRange => "POSITION_OUT_OF_RANGE",
+            DetectionError::SongIdOverflow => "SONG_ID_OVERFLOW",
+            DetectionError::ZerosAtEOF => "ZEROS_AT_EOF",
+            DetectionError::UnknownMagicLE32(_) => "UNKNOWN_MAGIC",
+        }
+    }
+}
+
+impl std::error::Error for DetectionError {}
+
+#[derive(Debug)]
+pub enum DecryptError {
+    Io(std::io::Error),
+    Detection(DetectionError),
+    Crypto(CryptoError),
+    /// The ekey stored in the file is not valid UTF-8.
+    EKeyNotUtf8,
+    /// Asked to start past the end of the audio data.
+    StartOutOfRange {
+        start: u64,
+        audio_len: u64,
+    },
+    Cancelled,
+    /// Keys start with an 8-byte header, so they cannot be shorter than that.
+    InvalidKeyLength(usize),
+    /// The ekey is too long to be found again behind a v1 trailer.
+    EKeyTooLongForV1(usize),
+    /// No registered format could make sense of the file.
+    UnknownFormat,
+    /// The format encrypts in blocks, so its audio cannot be read from any offset.
+    NotSeekable,
+    /// An ekey was given where it cannot be used.
+    UnexpectedEKey,
+}
+
+impl fmt::Display for DecryptError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            DecryptError::Io(err) => write!(f, "{}", err),
+            DecryptError::Detection(err) => write!(f, "{}", err),
+            DecryptError::Crypto(err) => write!(f, "{}", err),
+            DecryptError::EKeyNotUtf8 => write!(f, "ekey is not valid UTF-8"),
+            DecryptError::StartOutOfRange { start, audio_len } => {
+                write!(
+                    f,
+                    "start {} is beyond the audio data ({} bytes)",
+                    start, audio_len
+                )
+            }
+            DecryptError::Cancelled => write!(f, "cancelled"),
+            DecryptError::InvalidKeyLength(len) => {
+                write!(f, "key of {} bytes is too short, needs at least 8", len)
+            }
+            DecryptError::EKeyTooLongForV1(len) => {
+                write!(f, "ekey of {} bytes is too long for a v1 trailer", len)
+            }
+            DecryptError::UnknownFormat => write!(f, "not a known encrypted format"),
+            DecryptError::NotSeekable => write!(f, "this format cannot be read from any offset"),
+            DecryptError::UnexpectedEKey => {
+                write!(
+                    f,
+                    "an ekey cannot be given here, register QMC2Format::with_ekey instead"
+                )
+            }
+        }
+    }
+}
+
+impl DecryptError {
+    /// Stable identifier for bindings, which should not match on messages.
+    pub fn code(&self) -> &'static str {
+        match self {
+            DecryptError::Io(_) => "IO",
+            DecryptError::Detection(err) => err.code(),
+            DecryptError::Crypto(err) => err.code(),
+            DecryptError::EKeyNotUtf8 => "EKEY_NOT_UTF8",
+            DecryptError::StartOutOfRange { .. } => "START_OUT_OF_RANGE",
+            DecryptError::Cancelled => "CANCELLED",
+            DecryptError::InvalidKeyLength(_) => "INVALID_KEY_LENGTH",
+            DecryptError::EKeyTooLongForV1(_) => "EKEY_TOO_LONG",
+            DecryptError::UnknownFormat => "UNKNOWN_FORMAT",
+            DecryptError::NotSeekable => "NOT_SEEKABLE",
+            DecryptError::UnexpectedEKey => "UNEXPECTED_EKEY",
+        }
+    }
+}
+
+impl std::error::Error for DecryptError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            DecryptError::Io(err) => Some(err),
+            DecryptError::Detection(err) => Some(err),
+            DecryptError::Crypto(err) => Some(err),
+            _ => None,
+        }
+    }
+}
+
+impl From<std::io::Error> for DecryptError {
+    fn from(err: std::io::Error) -> Self {
+        DecryptError::Io(err)
+    }
+}
+
+impl From<DetectionError> for DecryptError {
+    fn from(err: DetectionError) -> Self {
+        DecryptError::Detection(err)
+    }
+}
+
+impl From<CryptoError> for DecryptError {
+    fn from(err: CryptoError) -> Self {
+        DecryptError::Crypto(err)
+    }
+}
@@ -0,0 +1,360 @@
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::ops::Range;
+
+use super::errors::DecryptError;
+use super::kwm::KwmFormat;
+#[cfg(feature = "ncm")]
+use super::ncm::NcmFormat;
+use super::qmc2::QMC2Format;
+use super::qmc2_base::{BlockCrypto, QMC2Crypto};
+use super::sniff::{AudioFormat, SNIFF_SIZE};
+use super::stream::{decrypt_blocks, decrypt_range, Options, RECOMMENDED_TAIL_SIZE};
+use super::tm::TmFormat;
+use super::trailer::TrailerKind;
+use super::xm::XmFormat;
+
+/// How much of the start of a file `Format::probe` gets.
+pub const PROBE_HEAD_SIZE: usize = 0x400;
+
+/// How much of the end of a file `Format::probe` gets.
+pub const PROBE_TAIL_SIZE: usize = RECOMMENDED_TAIL_SIZE;
+
+/// `Read + Seek`, as a trait object.
+pub trait ReadSeek: Read + Seek {}
+
+impl<T: Read + Seek + ?Sized> ReadSeek for T {}
+
+/// How sure a format is that it can open a file, from least to most.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Confidence {
+    No,
+    /// Only the extension matches, or the structure is too loose to be sure.
+    Maybe,
+    /// The structure parses and the extension agrees.
+    Likely,
+    /// An unambiguous magic value was found.
+    Certain,
+}
+
+/// How the audio data of a container is encrypted.
+pub enum Cipher {
+    /// Byte for byte, with offsets relative to the start of the audio data.
+    Stream(Box<dyn QMC2Crypto>),
+    /// In blocks that shrink when decrypted, starting at the audio data.
+    Block(Box<dyn BlockCrypto>),
+}
+
+/// Tags some containers carry next to the audio; empty for the others.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Metadata {
+    pub title: Option<String>,
+    pub artists: Vec<String>,
+    pub album: Option<String>,
+    /// What the container says the audio is, e.g. `"flac"`.
+    pub format: Option<String>,
+    /// In kbit/s.
+    pub bitrate: Option<u32>,
+    /// The cover image, as stored (usually JPEG or PNG).
+    pub cover: Option<Vec<u8>>,
+}
+
+/// A file whose key and audio data have been located by `Format::open`.
+pub struct Opened {
+    pub cipher: Cipher,
+    /// Where the encrypted audio lies in the file.
+    pub audio_range: Range<u64>,
+    /// Size of the decrypted audio; that of `audio_range` with `Cipher::Stream`.
+    pub audio_len: u64,
+    /// Song id stored by the container, or empty.
+    pub song_id: String,
+    pub metadata: Metadata,
+}
+
+/// A kind of encrypted container.
+pub trait Format: Send + Sync {
+    /// Short name for messages and reports, e.g. `"qmc2-qtag"`.
+    fn name(&self) -> &'static str;
+
+    /// Encrypted extensions (lowercase, without the dot) this format is
+    /// usually found under, and what the decrypted file should be called;
+    /// empty if only the decrypted audio can tell.
+    fn extensions(&self) -> &'static [(&'static str, &'static str)];
+
+    /// Look at the first `PROBE_HEAD_SIZE` and last `PROBE_TAIL_SIZE` bytes
+    /// of a file (the whole file in both, if smaller) and its extension.
+    fn probe(&self, head: &[u8], tail: &[u8], ext: &str) -> Confidence;
+
+    /// Find the key and the audio data, after `probe` said it could.
+    fn open(&self, file: &mut dyn ReadSeek) -> Result<Opened, DecryptError>;
+}
+
+/// Tries formats in order, picking the one most confident it can open a file.
+pub struct Registry {
+    formats: Vec<Box<dyn Format>>,
+}
+
+/// What `Registry::decrypt_stream` found and did.
+#[derive(Debug)]
+pub struct FileReport {
+    /// `Format::name` of the container.
+    pub container: &'static str,
+    pub song_id: String,
+    pub metadata: Metadata,
+    /// Guessed from the first decrypted bytes.
+    pub format: AudioFormat,
+    pub audio_len: u64,
+    pub bytes_written: u64,
+}
+
+impl Registry {
+    /// A registry that knows no format at all.
+    pub fn empty() -> Self {
+        Registry { formats: vec![] }
+    }
+
+    /// Add a format, tried after those already registered.
+    pub fn register<F: Format + 'static>(&mut self, format: F) -> &mut Self {
+        self.formats.push(Box::new(format));
+        self
+    }
+
+    pub fn formats(&self) -> impl Iterator<Item = &dyn Format> {
+        self.formats.iter().map(|format| &**format)
+    }
+
+    /// The most confident format; the first registered wins a tie.
+    pub fn probe(&self, head: &[u8], tail: &[u8], ext: &str) -> Option<&dyn Format> {
+        let ext = ext.to_ascii_lowercase();
+        let mut best = None;
+        for format in self.formats() {
+            let confidence = format.probe(head, tail, &ext);
+            if confidence > best.map_or(Confidence::No, |(c, _)| c) {
+                best = Some((confidence, format));
+            }
+        }
+        best.map(|(_, format)| format)
+    }
+
+    /// Name the decrypted copy of a file with extension `ext` should get,
+    /// if any format is known to use that extension.
+    pub fn decrypted_extension(&self, ext: &str) -> Option<&'static str> {
+        let ext = ext.to_ascii_lowercase();
+        self.formats()
+            .flat_map(|format| format.extensions())
+            .find(|(encrypted, _)| ext == *encrypted)
+            .map(|&(_, decrypted)| decrypted)
+    }
+
+    /// Probe `file` and open it with the format found.
+    pub fn open(
+        &self,
+        file: &mut dyn ReadSeek,
+        ext: &str,
+    ) -> Result<(&dyn Format, Opened), DecryptError> {
+        let file_len = file.seek(SeekFrom::End(0))?;
+
+        let mut head = vec![0u8; std::cmp::min(file_len, PROBE_HEAD_SIZE as u64) as usize];
+        file.seek(SeekFrom::Start(0))?;
+        file.read_exact(&mut head)?;
+
+        let tail_len = std::cmp::min(file_len, PROBE_TAIL_SIZE as u64);
+        let mut tail = vec![0u8; tail_len as usize];
+        file.seek(SeekFrom::Start(file_len - tail_len))?;
+        file.read_exact(&mut tail)?;
+
+        let format = self
+            .probe(&head, &tail, ext)
+            .ok_or(DecryptError::UnknownFormat)?;
+        Ok((format, format.open(file)?))
+    }
+
+    /// Like `stream::decrypt_stream`, for any registered format.
+    ///
+    /// `ext` is the extension of the file, which helps tell apart formats
+    /// without a magic value. `options.ekey` must not be set: formats get
+    /// their keys from the file, or from how they were registered (see
+    /// `QMC2Format::with_ekey`).
+    pub fn decrypt_stream<R, W>(
+        &self,
+        reader: &mut R,
+        writer: &mut W,
+        ext: &str,
+        options: &Options,
+    ) -> Result<FileReport, DecryptError>
+    where
+        R: Read + Seek,
+        W: Write + ?Sized,
+    {
+        if options.ekey.is_some() {
+            return Err(DecryptError::UnexpectedEKey);
+        }
+        let (format, opened) = self.open(reader, ext)?;
+        let (audio_format, bytes_written) = match &opened.cipher {
+            Cipher::Stream(crypto) => {
+                decrypt_range(reader, writer, &**crypto, &opened.audio_range, options)?
+            }
+            Cipher::Block(crypto) => decrypt_blocks(
+                reader,
+                writer,
+                &**crypto,
+                &opened.audio_range,
+                opened.audio_len,
+                options,
+            )?,
+        };
+
+        Ok(FileReport {
+            container: format.name(),
+            song_id: opened.song_id,
+            metadata: opened.metadata,
+            format: audio_format,
+            audio_len: opened.audio_len,
+            bytes_written,
+        })
+    }
+
+    /// Guess the format of the decrypted audio, decrypting as little as possible.
+    pub fn sniff(&self, file: &mut dyn ReadSeek, ext: &str) -> Result<AudioFormat, DecryptError> {
+        let (_, opened) = self.open(file, ext)?;
+        let range = &opened.audio_range;
+        let head_len = match &opened.cipher {
+            Cipher::Stream(_) => SNIFF_SIZE as u64,
+            Cipher::Block(crypto) => crypto.encrypted_block_size() as u64,
+        };
+
+        let mut head = vec![0u8; std::cmp::min(range.end - range.start, head_len) as usize];
+        file.seek(SeekFrom::Start(range.start))?;
+        file.read_exact(&mut head)?;
+        let head_len = match &opened.cipher {
+            Cipher::Stream(crypto) => {
+                crypto.decrypt(0, &mut head);
+                head.len()
+            }
+            Cipher::Block(crypto) => crypto.decrypt_block(&mut head)?,
+        };
+        Ok(AudioFormat::sniff(&head[..head_len]))
+    }
+}
+
+impl Default for Registry {
+    /// Every format built in that needs nothing but the file itself.
+    fn default() -> Self {
+        let mut registry = Registry::empty();
+        registry
+            .register(QMC2Format::new(TrailerKind::QTag))
+            .register(QMC2Format::new(TrailerKind::V1))
+            .register(TmFormat::M4a)
+            .register(TmFormat::Mp3)
+            .register(KwmFormat)
+            .register(XmFormat);
+        #[cfg(feature = "ncm")]
+        registry.register(NcmFormat);
+        registry
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::crypto::key_dec::generate_ekey;
+    use crate::crypto::qmc2::crypto_from_key;
+    use crate::crypto::test_util::{decrypt_all, test_audio, test_key, SAWTOOTH_FLAC, SONG_ID};
+    use crate::crypto::trailer::{build_qtag_trailer, build_v1_trailer};
+    use std::io::Cursor;
+
+    fn encrypted_file(trailer: TrailerKind) -> (Vec<u8>, Vec<u8>) {
+        let key = test_key(256);
+        let audio = test_audio(b"OggS", 5000);
+
+        let mut data = audio.clone();
+        crypto_from_key(&key).unwrap().encrypt(0, &mut data);
+        data.extend(match trailer {
+            TrailerKind::V1 => build_v1_trailer(&generate_ekey(&key)),
+            TrailerKind::QTag => build_qtag_trailer(&generate_ekey(&key), SONG_ID),
+        });
+        (data, audio)
+    }
+
+    #[test]
+    fn test_probe() {
+        let registry = Registry::default();
+        let (v2, _) = encrypted_file(TrailerKind::QTag);
+        let (v1, _) = encrypted_file(TrailerKind::V1);
+        let tail = |data: &[u8]| data[data.len() - PROBE_TAIL_SIZE..].to_vec();
+
+        let name = |ext| registry.probe(&v2, &tail(&v2), ext).map(|f| f.name());
+        assert_eq!(name("mflac"), Some("qmc2-qtag"));
+        assert_eq!(name(""), Some("qmc2-qtag"));
+
+        let name = |ext| registry.probe(&v1, &tail(&v1), ext).map(|f| f.name());
+        assert_eq!(name("MGG1"), Some("qmc2-v1"));
+        assert_eq!(name("flac"), Some("qmc2-v1"));
+
+        assert!(registry.probe(b"fLaC", &[0u8; 16], "flac").is_none());
+    }
+
+    #[test]
+    fn test_decrypt_stream() {
+        for trailer in [TrailerKind::QTag, TrailerKind::V1] {
+            let (data, audio) = encrypted_file(trailer);
+            let (output, report) = decrypt_all(&Registry::default(), &data, "mgg");
+            assert_eq!(output, audio);
+            assert_eq!(report.format, AudioFormat::Ogg);
+            assert_eq!(report.audio_len, 5000);
+        }
+    }
+
+    #[test]
+    fn test_fixture() {
+        let file = include_bytes!("../../fixtures/sawtooth.mflac");
+        let (output, report) = decrypt_all(&Registry::default(), file, "mflac");
+        assert_eq!(output, SAWTOOTH_FLAC);
+        assert_eq!(report.container, "qmc2-qtag");
+        assert_eq!(report.format, AudioFormat::Flac);
+        assert_eq!(report.song_id, SONG_ID);
+    }
+
+    #[test]
+    fn test_sniff() {
+        let (data, _) = encrypted_file(TrailerKind::QTag);
+        let format = Registry::default().sniff(&mut Cursor::new(data), "mgg");
+        assert_eq!(format.unwrap(), AudioFormat::Ogg);
+    }
+
+    #[test]
+    fn test_unknown_format() {
+        let err = Registry::default()
+            .decrypt_stream(
+                &mut Cursor::new(vec![0u8; 100]),
+                &mut vec![],
+                "mp3",
+                &Default::default(),
+            )
+            .unwrap_err();
+        assert_eq!(err.code(), "UNKNOWN_FORMAT");
+    }
+
+    #[test]
+    fn test_ekey_option_rejected() {
+        let (data, _) = encrypted_file(TrailerKind::QTag);
+        let options = Options {
+            ekey: Some("ekey"),
+            ..Default::default()
+        };
+        let err = Registry::default()
+            .decrypt_stream(&mut Cursor::new(data), &mut vec![], "mgg", &options)
+            .unwrap_err();
+        assert_eq!(err.code(), "UNEXPECTED_EKEY");
+    }
+
+    #[test]
+    fn test_decrypted_extension() {
+        let registry = Registry::default();
+        assert_eq!(registry.decrypted_extension("mflac"), Some("flac"));
+        assert_eq!(registry.decrypted_extension("MGG1"), Some("ogg"));
+        assert_eq!(registry.decrypted_extension("mmp4"), Some("mp4"));
+        assert_eq!(registry.decrypted_extension("flac"), None);
+        assert_eq!(registry.decrypted_extension("mflac9"), None);
+        assert_eq!(registry.decrypted_extension("xml"), None);
+    }
+}
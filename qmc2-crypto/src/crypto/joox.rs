@@ -0,0 +1,229 @@
+use aes::cipher::{BlockDecrypt, KeyInit};
+use aes::Aes128;
+use sha1::Sha1;
+use std::io::SeekFrom;
+
+use super::errors::{CryptoError, DecryptError, DetectionError};
+use super::format::{Cipher, Confidence, Format, Opened, ReadSeek};
+use super::pkcs7::padding_len;
+use super::qmc2_base::BlockCrypto;
+
+/// `E!04`, then 8 bytes (the size of the audio) that are not needed to decrypt.
+const MAGIC_V4: &[u8; 4] = b"E!04";
+const HEADER_SIZE: u64 = 12;
+
+/// Every 1 MiB of audio is encrypted on its own, with PKCS#7 padding.
+const DECRYPTED_BLOCK_SIZE: usize = 0x100000;
+const ENCRYPTED_BLOCK_SIZE: usize = DECRYPTED_BLOCK_SIZE + AES_BLOCK_SIZE;
+const AES_BLOCK_SIZE: usize = 16;
+
+const KEY_SALT: [u8; 16] = [
+    0xa4, 0x0b, 0xc8, 0x34, 0xd6, 0x95, 0xf3, 0x13, //
+    0x23, 0x23, 0x43, 0x23, 0x54, 0x63, 0x83, 0xf3, //
+];
+const KEY_ROUNDS: u32 = 1000;
+
+/// The AES key of a device, derived from its UUID.
+fn derive_key(uuid: &str) -> [u8; 16] {
+    let mut key = [0u8; 16];
+    pbkdf2::pbkdf2_hmac::<Sha1>(uuid.as_bytes(), &KEY_SALT, KEY_ROUNDS, &mut key);
+    key
+}
+
+/// AES-128-ECB over blocks of `ENCRYPTED_BLOCK_SIZE` bytes.
+pub struct JooxCrypto {
+    aes: Aes128,
+}
+
+impl JooxCrypto {
+    pub fn new(uuid: &str) -> Self {
+        JooxCrypto {
+            aes: Aes128::new(&derive_key(uuid).into()),
+        }
+    }
+}
+
+impl BlockCrypto for JooxCrypto {
+    fn encrypted_block_size(&self) -> usize {
+        ENCRYPTED_BLOCK_SIZE
+    }
+
+    fn decrypted_block_size(&self) -> usize {
+        DECRYPTED_BLOCK_SIZE
+    }
+
+    fn decrypt_block(&self, block: &mut [u8]) -> Result<usize, CryptoError> {
+        if block.is_empty() || !block.len().is_multiple_of(AES_BLOCK_SIZE) {
+            return Err(CryptoError::InvalidPadding);
+        }
+
+        for chunk in block.chunks_exact_mut(AES_BLOCK_SIZE) {
+            self.aes.decrypt_block(chunk.into());
+        }
+        let padding = padding_len(&block[block.len() - AES_BLOCK_SIZE..])?;
+        Ok(block.len() - padding)
+    }
+}
+
+/// JOOX v4 files: a small header, then the audio in AES blocks, keyed by
+/// the UUID of the device that downloaded them.
+pub struct JooxFormat {
+    uuid: String,
+}
+
+impl JooxFormat {
+    pub fn new(uuid: &str) -> Self {
+        JooxFormat { uuid: uuid.into() }
+    }
+}
+
+impl Format for JooxFormat {
+    fn name(&self) -> &'static str {
+        "joox-v4"
+    }
+
+    fn extensions(&self) -> &'static [(&'static str, &'static str)] {
+        // Could be MP3, M4A or FLAC.
+        &[("ofl_en", "")]
+    }
+
+    fn probe(&self, head: &[u8], _tail: &[u8], _ext: &str) -> Confidence {
+        match head.starts_with(MAGIC_V4) {
+            true => Confidence::Certain,
+            false => Confidence::No,
+        }
+    }
+
+    fn open(&self, file: &mut dyn ReadSeek) -> Result<Opened, DecryptError> {
+        let file_len = file.seek(SeekFrom::End(0))?;
+        let encrypted_len = file_len.saturating_sub(HEADER_SIZE);
+        let block_count = encrypted_len.div_ceil(ENCRYPTED_BLOCK_SIZE as u64);
+        let last_block_len = encrypted_len - (block_count.max(1) - 1) * ENCRYPTED_BLOCK_SIZE as u64;
+        if last_block_len < AES_BLOCK_SIZE as u64 {
+            return Err(DetectionError::BufferTooSmall.into());
+        }
+
+        let mut header = [0u8; HEADER_SIZE as usize];
+        file.seek(SeekFrom::Start(0))?;
+        file.read_exact(&mut header)?;
+        if !header.starts_with(MAGIC_V4) {
+            return Err(DecryptError::UnknownFormat);
+        }
+
+        // The padding of the last block tells how long the audio is; with
+        // ECB, its last AES block can be decrypted on its own.
+        let crypto = JooxCrypto::new(&self.uuid);
+        let mut last = [0u8; AES_BLOCK_SIZE];
+        file.seek(SeekFrom::End(-(AES_BLOCK_SIZE as i64)))?;
+        file.read_exact(&mut last)?;
+        crypto.aes.decrypt_block((&mut last).into());
+        let padding = padding_len(&last)? as u64;
+
+        Ok(Opened {
+            cipher: Cipher::Block(Box::new(crypto)),
+            audio_range: HEADER_SIZE..file_len,
+            audio_len: (block_count - 1) * DECRYPTED_BLOCK_SIZE as u64 + last_block_len - padding,
+            song_id: "".into(),
+            metadata: Default::default(),
+        })
+    }
+}
+
+/// Encrypt `audio` into a JOOX v4 file for the device `uuid`, to make test
+/// fixtures.
+#[cfg(any(test, feature = "test-support"))]
+pub fn encrypt_v4(uuid: &str, audio: &[u8]) -> Vec<u8> {
+    use aes::cipher::BlockEncrypt;
+
+    let aes = Aes128::new(&derive_key(uuid).into());
+
+    let mut file = MAGIC_V4.to_vec();
+    file.extend((audio.len() as u64).to_be_bytes());
+    // An empty file still gets a block of padding.
+    let blocks = audio.chunks(DECRYPTED_BLOCK_SIZE);
+    for block in blocks.chain(audio.is_empty().then_some(&[][..])) {
+        let padding = AES_BLOCK_SIZE - block.len() % AES_BLOCK_SIZE;
+        let start = file.len();
+        file.extend(block);
+        file.resize(file.len() + padding, padding as u8);
+        for chunk in file[start..].chunks_exact_mut(AES_BLOCK_SIZE) {
+            aes.encrypt_block(chunk.into());
+        }
+    }
+    file
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::crypto::format::Registry;
+    use crate::crypto::sniff::AudioFormat;
+    use crate::crypto::stream::Options;
+    use crate::crypto::test_util::{decrypt_all, test_audio, SAWTOOTH_FLAC};
+    use std::io::Cursor;
+
+    const UUID: &str = "ffffffffffffffffffffffffffffffff";
+
+    fn registry(uuid: &str) -> Registry {
+        let mut registry = Registry::default();
+        registry.register(JooxFormat::new(uuid));
+        registry
+    }
+
+    fn mp3(len: usize) -> Vec<u8> {
+        test_audio(b"ID3", len)
+    }
+
+    #[test]
+    fn test_decrypt_v4() {
+        // A full block, exactly; then one and a bit.
+        for len in [DECRYPTED_BLOCK_SIZE, DECRYPTED_BLOCK_SIZE + 1000] {
+            let audio = mp3(len);
+            let file = encrypt_v4(UUID, &audio);
+
+            let (output, report) = decrypt_all(&registry(UUID), &file, "ofl_en");
+            assert_eq!(report.container, "joox-v4");
+            assert_eq!(report.audio_len, len as u64);
+            assert_eq!(report.format, AudioFormat::Mp3);
+            assert_eq!(output, audio);
+        }
+    }
+
+    #[test]
+    fn test_resume_v4() {
+        let audio = mp3(DECRYPTED_BLOCK_SIZE * 2 + 5);
+        let file = encrypt_v4(UUID, &audio);
+
+        for start in [10, DECRYPTED_BLOCK_SIZE as u64 + 3, audio.len() as u64] {
+            let mut output = vec![];
+            let options = Options {
+                start,
+                ..Default::default()
+            };
+            let report = registry(UUID)
+                .decrypt_stream(&mut Cursor::new(&file), &mut output, "", &options)
+                .unwrap();
+            assert_eq!(report.format, AudioFormat::Mp3);
+            assert_eq!(output, &audio[start as usize..]);
+        }
+    }
+
+    #[test]
+    fn test_wrong_uuid() {
+        let file = encrypt_v4(UUID, &mp3(100));
+        let err = registry("00000000000000000000000000000000")
+            .decrypt_stream(&mut Cursor::new(file), &mut vec![], "", &Default::default())
+            .unwrap_err();
+        assert_eq!(err.code(), "INVALID_PADDING");
+    }
+
+    #[test]
+    fn test_fixture() {
+        // `encrypt_v4(UUID, SAWTOOTH_FLAC)`
+        let file = include_bytes!("../../fixtures/sawtooth.ofl_en");
+        let (output, report) = decrypt_all(&registry(UUID), file, "ofl_en");
+        assert_eq!(output, SAWTOOTH_FLAC);
+        assert_eq!(report.container, "joox-v4");
+        assert_eq!(report.format, AudioFormat::Flac);
+    }
+}
@@ -83,10 +83,10 @@ mod tests {
     #[test]
     fn test_generate_ekey() {
         let expected_key = b"12345678...test data by Jixun";
-        let ekey = generate_ekey(&expected_key);
+        let ekey = generate_ekey(expected_key);
         let actual = parse_ekey(&ekey).unwrap();
         assert_eq!(
-            std::str::from_utf8(&*actual).unwrap(),
+            std::str::from_utf8(&actual).unwrap(),
             std::str::from_utf8(expected_key).unwrap()
         );
     }
@@ -96,6 +96,6 @@ mod tests {
         let expected_key = "This is a test key for test purpose :D";
         let ekey = "VGhpcyBpcyBHFWEh4cjZ1Vi7rJ56XeoPlqGM1sxBGPg7mt89umKclFBr9iqfmFdS";
         let decoded_key = parse_ekey(ekey).unwrap();
-        assert_eq!(std::str::from_utf8(&*decoded_key).unwrap(), expected_key);
+        assert_eq!(std::str::from_utf8(&decoded_key).unwrap(), expected_key);
     }
 }
@@ -0,0 +1,177 @@
+use std::io::SeekFrom;
+
+use super::errors::{DecryptError, DetectionError};
+use super::format::{Cipher, Confidence, Format, Metadata, Opened, ReadSeek};
+use super::qmc2_base::QMC2Crypto;
+
+/// Followed by `-tme` or zeros.
+const MAGIC: &[u8] = b"yeelion-kuwo";
+const HEADER_SIZE: usize = 0x400;
+/// u64, whose decimal digits make the key.
+const RESOURCE_ID_POSITION: usize = 0x18;
+/// e.g. `320kmp3` or `2000kflac`, padded with zeros.
+const QUALITY_POSITION: usize = 0x30;
+const QUALITY_LEN: usize = 0x10;
+
+const KEY_LEN: usize = 32;
+const BASE_KEY: &[u8; KEY_LEN] = b"MoOtOiTvINGwd2E6n0E1i7L5t2IoOoNk";
+
+/// XORs the audio with a 32-byte key.
+pub struct KwmCrypto {
+    key: [u8; KEY_LEN],
+}
+
+impl KwmCrypto {
+    pub fn new(resource_id: u64) -> Self {
+        let digits = resource_id.to_string().into_bytes();
+        let mut key = *BASE_KEY;
+        for (i, value) in key.iter_mut().enumerate() {
+            *value ^= digits[i % digits.len()];
+        }
+        KwmCrypto { key }
+    }
+}
+
+impl QMC2Crypto for KwmCrypto {
+    fn get_recommended_block_size(&self) -> usize {
+        0x10000
+    }
+
+    fn decrypt(&self, offset: u64, buf: &mut [u8]) {
+        for (offset, value) in (offset..).zip(buf.iter_mut()) {
+            *value ^= self.key[(offset % KEY_LEN as u64) as usize];
+        }
+    }
+}
+
+/// Bitrate (in kbit/s) and format from a quality field such as `320kmp3`.
+fn parse_quality(field: &[u8]) -> (Option<u32>, Option<String>) {
+    let end = field.iter().position(|&b| b == 0).unwrap_or(field.len());
+    let Ok(quality) = std::str::from_utf8(&field[..end]) else {
+        return (None, None);
+    };
+    let Some((bitrate, format)) = quality.split_once('k') else {
+        return (None, None);
+    };
+    let format = Some(format.to_ascii_lowercase()).filter(|format| !format.is_empty());
+    (bitrate.parse().ok(), format)
+}
+
+/// Kuwo files (`.kwm`): a 1 KiB header, then the audio data.
+pub struct KwmFormat;
+
+impl Format for KwmFormat {
+    fn name(&self) -> &'static str {
+        "kwm"
+    }
+
+    fn extensions(&self) -> &'static [(&'static str, &'static str)] {
+        // The quality field in the header says which, see `parse_quality`.
+        &[("kwm", "")]
+    }
+
+    fn probe(&self, head: &[u8], _tail: &[u8], _ext: &str) -> Confidence {
+        match head.starts_with(MAGIC) {
+            true => Confidence::Certain,
+            false => Confidence::No,
+        }
+    }
+
+    fn open(&self, file: &mut dyn ReadSeek) -> Result<Opened, DecryptError> {
+        let file_len = file.seek(SeekFrom::End(0))?;
+        if file_len < HEADER_SIZE as u64 {
+            return Err(DetectionError::BufferTooSmall.into());
+        }
+
+        let mut header = [0u8; HEADER_SIZE];
+        file.seek(SeekFrom::Start(0))?;
+        file.read_exact(&mut header)?;
+        if !header.starts_with(MAGIC) {
+            return Err(DecryptError::UnknownFormat);
+        }
+
+        let id = &header[RESOURCE_ID_POSITION..RESOURCE_ID_POSITION + 8];
+        let resource_id = u64::from_le_bytes(id.try_into().unwrap());
+        let (bitrate, format) =
+            parse_quality(&header[QUALITY_POSITION..QUALITY_POSITION + QUALITY_LEN]);
+
+        Ok(Opened {
+            cipher: Cipher::Stream(Box::new(KwmCrypto::new(resource_id))),
+            audio_range: HEADER_SIZE as u64..file_len,
+            audio_len: file_len - HEADER_SIZE as u64,
+            song_id: resource_id.to_string(),
+            metadata: Metadata {
+                bitrate,
+                format,
+                ..Default::default()
+            },
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::crypto::format::Registry;
+    use crate::crypto::sniff::AudioFormat;
+    use crate::crypto::test_util::{decrypt_all, test_audio, SAWTOOTH_FLAC};
+
+    fn encrypted_file(resource_id: u64, quality: &[u8], audio: &[u8]) -> Vec<u8> {
+        let mut file = vec![0u8; HEADER_SIZE];
+        file[..16].copy_from_slice(b"yeelion-kuwo-tme");
+        file[RESOURCE_ID_POSITION..RESOURCE_ID_POSITION + 8]
+            .copy_from_slice(&resource_id.to_le_bytes());
+        file[QUALITY_POSITION..QUALITY_POSITION + quality.len()].copy_from_slice(quality);
+
+        let mut data = audio.to_vec();
+        KwmCrypto::new(resource_id).encrypt(0, &mut data);
+        file.extend(data);
+        file
+    }
+
+    #[test]
+    fn test_key() {
+        // 32 bytes of digits, repeated from the start.
+        let crypto = KwmCrypto::new(1234567);
+        assert_eq!(crypto.key[0], b'M' ^ b'1');
+        assert_eq!(crypto.key[7], b'v' ^ b'1');
+        assert_eq!(crypto.key[31], b'k' ^ b'4');
+    }
+
+    #[test]
+    fn test_parse_quality() {
+        assert_eq!(
+            parse_quality(b"320kmp3\0\0"),
+            (Some(320), Some("mp3".into()))
+        );
+        assert_eq!(
+            parse_quality(b"2000kflac"),
+            (Some(2000), Some("flac".into()))
+        );
+        assert_eq!(parse_quality(b"\0\0\0"), (None, None));
+    }
+
+    #[test]
+    fn test_decrypt_kwm() {
+        let audio = test_audio(b"fLaC", 50000);
+        let file = encrypted_file(156483846, b"2000kflac", &audio);
+
+        let (output, report) = decrypt_all(&Registry::default(), &file, "kwm");
+        assert_eq!(output, audio);
+        assert_eq!(report.container, "kwm");
+        assert_eq!(report.format, AudioFormat::Flac);
+        assert_eq!(report.song_id, "156483846");
+        assert_eq!(report.metadata.bitrate, Some(2000));
+        assert_eq!(report.metadata.format.as_deref(), Some("flac"));
+    }
+
+    #[test]
+    fn test_fixture() {
+        // `encrypted_file(156483846, b"2000kflac", SAWTOOTH_FLAC)`
+        let file = include_bytes!("../../fixtures/sawtooth.kwm");
+        let (output, report) = decrypt_all(&Registry::default(), file, "kwm");
+        assert_eq!(output, SAWTOOTH_FLAC);
+        assert_eq!(report.song_id, "156483846");
+        assert_eq!(report.metadata.format.as_deref(), Some("flac"));
+    }
+}
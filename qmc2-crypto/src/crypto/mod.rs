@@ -1,8 +1,27 @@
+#[cfg(feature = "tokio")]
+pub mod async_reader;
 pub mod detection;
 pub mod errors;
+pub mod format;
+#[cfg(feature = "joox")]
+pub mod joox;
 pub mod key_dec;
+pub mod kwm;
+#[cfg(feature = "ncm")]
+pub mod ncm;
+#[cfg(any(feature = "joox", feature = "ncm"))]
+mod pkcs7;
 pub mod qmc2;
 pub mod qmc2_base;
 mod qmc2_map;
 mod qmc2_rc4;
+pub mod reader;
+pub mod rewrap;
+pub mod sniff;
+pub mod stream;
 mod stream_utils;
+#[cfg(any(test, feature = "test-support"))]
+pub mod test_util;
+pub mod tm;
+pub mod trailer;
+pub mod xm;
@@ -0,0 +1,317 @@
+use aes::cipher::{BlockDecrypt, KeyInit};
+use aes::Aes128;
+use std::io::SeekFrom;
+
+use super::errors::{CryptoError, DecryptError, DetectionError};
+use super::format::{Cipher, Confidence, Format, Metadata, Opened, ReadSeek};
+use super::pkcs7::padding_len;
+use super::qmc2_base::QMC2Crypto;
+
+/// `CTENFDAM`, then 2 bytes that are not needed.
+const MAGIC: &[u8; 8] = b"CTENFDAM";
+const KEY_LEN_POSITION: u64 = 10;
+
+/// Decrypts the RC4 key of every file.
+const CORE_KEY: &[u8; 16] = b"hzHRAmso5kInbaxW";
+/// Decrypts the metadata of every file.
+const META_KEY: &[u8; 16] = b"#14ljk_!\\]&0U<'(";
+
+const KEY_XOR: u8 = 0x64;
+const KEY_PREFIX: &[u8] = b"neteasecloudmusic";
+const META_XOR: u8 = 0x63;
+const META_PREFIX: &[u8] = b"163 key(Don't modify):";
+const META_JSON_PREFIX: &[u8] = b"music:";
+
+/// A CRC32 and 5 bytes that are not needed, between the metadata and the cover.
+const GAP_AFTER_META: i64 = 9;
+
+/// Decrypt AES-128-ECB with PKCS#7 padding.
+fn aes_ecb_decrypt(key: &[u8; 16], data: &mut Vec<u8>) -> Result<(), CryptoError> {
+    if data.is_empty() || !data.len().is_multiple_of(16) {
+        return Err(CryptoError::InvalidPadding);
+    }
+
+    let aes = Aes128::new(key.into());
+    for chunk in data.chunks_exact_mut(16) {
+        aes.decrypt_block(chunk.into());
+    }
+    let padding = padding_len(&data[data.len() - 16..])?;
+    data.truncate(data.len() - padding);
+    Ok(())
+}
+
+/// An RC4 variant whose key stream repeats every 256 bytes.
+pub struct NcmCrypto {
+    key_stream: [u8; 256],
+}
+
+impl NcmCrypto {
+    pub fn new(key: &[u8]) -> Self {
+        let mut key_box: Vec<u8> = (0..=255).collect();
+        let mut last = 0u8;
+        for i in 0..256 {
+            let j = key_box[i]
+                .wrapping_add(last)
+                .wrapping_add(key[i % key.len()]);
+            key_box.swap(i, j as usize);
+            last = j;
+        }
+
+        let mut key_stream = [0u8; 256];
+        for (i, value) in key_stream.iter_mut().enumerate() {
+            let j = key_box[i] as usize;
+            let k = key_box[(j + i) & 0xff] as usize;
+            *value = key_box[(j + k) & 0xff];
+        }
+        NcmCrypto { key_stream }
+    }
+}
+
+impl QMC2Crypto for NcmCrypto {
+    fn get_recommended_block_size(&self) -> usize {
+        0x8000
+    }
+
+    fn decrypt(&self, offset: u64, buf: &mut [u8]) {
+        for (offset, value) in (offset..).zip(buf.iter_mut()) {
+            *value ^= self.key_stream[((offset + 1) & 0xff) as usize];
+        }
+    }
+}
+
+/// Read a u32 length, then that many bytes, which must fit in the file.
+fn read_block(file: &mut dyn ReadSeek, file_len: u64) -> Result<Vec<u8>, DecryptError> {
+    let mut len = [0u8; 4];
+    file.read_exact(&mut len)?;
+    let len = u32::from_le_bytes(len) as u64;
+    if len > file_len {
+        return Err(DetectionError::BufferTooSmall.into());
+    }
+
+    let mut block = vec![0u8; len as usize];
+    file.read_exact(&mut block)?;
+    Ok(block)
+}
+
+/// The RC4 key, from the key block.
+fn parse_key(mut block: Vec<u8>) -> Result<Vec<u8>, DecryptError> {
+    block.iter_mut().for_each(|b| *b ^= KEY_XOR);
+    aes_ecb_decrypt(CORE_KEY, &mut block)?;
+    match block.strip_prefix(KEY_PREFIX) {
+        Some(key) if !key.is_empty() => Ok(key.to_vec()),
+        _ => Err(CryptoError::EKeyParseError.into()),
+    }
+}
+
+/// The JSON in the metadata block, if it can be read; files play without it.
+fn parse_metadata_json(mut block: Vec<u8>) -> Option<serde_json::Value> {
+    block.iter_mut().for_each(|b| *b ^= META_XOR);
+    let mut json = base64::decode(block.strip_prefix(META_PREFIX)?).ok()?;
+    aes_ecb_decrypt(META_KEY, &mut json).ok()?;
+    serde_json::from_slice(json.strip_prefix(META_JSON_PREFIX)?).ok()
+}
+
+/// Song id and metadata from the JSON, e.g.
+/// `{"musicId":1,"musicName":"...","artist":[["name",2]],"album":"...","bitrate":320000,"format":"mp3"}`.
+fn metadata_from_json(json: &serde_json::Value) -> (String, Metadata) {
+    let string = |key: &str| json[key].as_str().map(String::from);
+    let song_id = match &json["musicId"] {
+        serde_json::Value::Number(id) => id.to_string(),
+        id => id.as_str().unwrap_or("").into(),
+    };
+    let artists = json["artist"]
+        .as_array()
+        .map(|artists| {
+            artists
+                .iter()
+                .filter_map(|artist| artist[0].as_str().map(String::from))
+                .collect()
+        })
+        .unwrap_or_default();
+
+    let metadata = Metadata {
+        title: string("musicName"),
+        artists,
+        album: string("album"),
+        format: string("format"),
+        bitrate: json["bitrate"].as_u64().map(|bps| (bps / 1000) as u32),
+        cover: None,
+    };
+    (song_id, metadata)
+}
+
+/// NetEase Cloud Music files (`.ncm`): a header with the key, metadata and
+/// cover, then the audio data.
+pub struct NcmFormat;
+
+impl Format for NcmFormat {
+    fn name(&self) -> &'static str {
+        "ncm"
+    }
+
+    fn extensions(&self) -> &'static [(&'static str, &'static str)] {
+        // The metadata says which, if there is any.
+        &[("ncm", "")]
+    }
+
+    fn probe(&self, head: &[u8], _tail: &[u8], _ext: &str) -> Confidence {
+        match head.starts_with(MAGIC) {
+            true => Confidence::Certain,
+            false => Confidence::No,
+        }
+    }
+
+    fn open(&self, file: &mut dyn ReadSeek) -> Result<Opened, DecryptError> {
+        let file_len = file.seek(SeekFrom::End(0))?;
+        let mut magic = [0u8; MAGIC.len()];
+        file.seek(SeekFrom::Start(0))?;
+        file.read_exact(&mut magic)?;
+        if magic != *MAGIC {
+            return Err(DecryptError::UnknownFormat);
+        }
+
+        file.seek(SeekFrom::Start(KEY_LEN_POSITION))?;
+        let key = parse_key(read_block(file, file_len)?)?;
+        let json = parse_metadata_json(read_block(file, file_len)?);
+        let (song_id, mut metadata) = json.as_ref().map(metadata_from_json).unwrap_or_default();
+
+        file.seek(SeekFrom::Current(GAP_AFTER_META))?;
+        let cover = read_block(file, file_len)?;
+        metadata.cover = (!cover.is_empty()).then_some(cover);
+
+        let audio_start = file.stream_position()?;
+        Ok(Opened {
+            cipher: Cipher::Stream(Box::new(NcmCrypto::new(&key))),
+            audio_range: audio_start..file_len,
+            audio_len: file_len - audio_start,
+            song_id,
+            metadata,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::crypto::format::Registry;
+    use crate::crypto::reader::QMC2Reader;
+    use crate::crypto::sniff::AudioFormat;
+    use crate::crypto::test_util::{decrypt_all, test_audio, test_key, SAWTOOTH_FLAC};
+    use aes::cipher::BlockEncrypt;
+    use std::io::{Cursor, Read, Seek};
+
+    fn aes_ecb_encrypt(key: &[u8; 16], data: &[u8]) -> Vec<u8> {
+        let padding = 16 - data.len() % 16;
+        let mut data = data.to_vec();
+        data.resize(data.len() + padding, padding as u8);
+        let aes = Aes128::new(key.into());
+        for chunk in data.chunks_exact_mut(16) {
+            aes.encrypt_block(chunk.into());
+        }
+        data
+    }
+
+    fn block(data: &[u8]) -> Vec<u8> {
+        let mut block = (data.len() as u32).to_le_bytes().to_vec();
+        block.extend(data);
+        block
+    }
+
+    fn encrypted_file(json: &str, cover: &[u8], audio: &[u8]) -> Vec<u8> {
+        let key = test_key(128);
+
+        let mut file = MAGIC.to_vec();
+        file.extend([0, 0]);
+
+        let mut key_block = aes_ecb_encrypt(CORE_KEY, &[KEY_PREFIX, &key].concat());
+        key_block.iter_mut().for_each(|b| *b ^= KEY_XOR);
+        file.extend(block(&key_block));
+
+        let meta = aes_ecb_encrypt(META_KEY, &[META_JSON_PREFIX, json.as_bytes()].concat());
+        let mut meta_block = [META_PREFIX, base64::encode(meta).as_bytes()].concat();
+        meta_block.iter_mut().for_each(|b| *b ^= META_XOR);
+        file.extend(block(&meta_block));
+
+        file.extend([0u8; GAP_AFTER_META as usize]);
+        file.extend(block(cover));
+
+        let mut data = audio.to_vec();
+        NcmCrypto::new(&key).encrypt(0, &mut data);
+        file.extend(data);
+        file
+    }
+
+    const JSON: &str = r#"{"musicId":1234,"musicName":"Title","artist":[["A",1],["B",2]],"album":"Album","bitrate":999000,"format":"flac"}"#;
+
+    #[test]
+    fn test_decrypt_ncm() {
+        let audio = test_audio(b"fLaC", 50000);
+        let file = encrypted_file(JSON, b"\xff\xd8cover", &audio);
+
+        let (output, report) = decrypt_all(&Registry::default(), &file, "ncm");
+        assert_eq!(output, audio);
+        assert_eq!(report.container, "ncm");
+        assert_eq!(report.format, AudioFormat::Flac);
+        assert_eq!(report.song_id, "1234");
+        assert_eq!(
+            report.metadata,
+            Metadata {
+                title: Some("Title".into()),
+                artists: vec!["A".into(), "B".into()],
+                album: Some("Album".into()),
+                format: Some("flac".into()),
+                bitrate: Some(999),
+                cover: Some(b"\xff\xd8cover".to_vec()),
+            }
+        );
+    }
+
+    #[test]
+    fn test_without_metadata() {
+        let audio = test_audio(b"fLaC", 50000);
+        let mut file = encrypted_file("not json", b"", &audio);
+        // The metadata block can be missing altogether, too.
+        let key_len = u32::from_le_bytes(file[10..14].try_into().unwrap());
+        let meta_start = KEY_LEN_POSITION as usize + 4 + key_len as usize;
+        let meta_len = u32::from_le_bytes(file[meta_start..meta_start + 4].try_into().unwrap());
+        file.splice(meta_start..meta_start + 4 + meta_len as usize, [0u8; 4]);
+
+        let mut reader = QMC2Reader::open(Cursor::new(file), &Registry::default(), "ncm").unwrap();
+        assert_eq!(reader.song_id(), "");
+        assert!(reader.detection().is_none());
+        reader.seek(SeekFrom::Start(1000)).unwrap();
+        let mut buf = [0u8; 100];
+        reader.read_exact(&mut buf).unwrap();
+        assert_eq!(buf, &audio[1000..1100]);
+    }
+
+    #[test]
+    fn test_bad_key() {
+        let mut file = encrypted_file("{}", b"", &test_audio(b"fLaC", 100));
+        // Only garbles the first AES block, so the padding is still fine.
+        file[KEY_LEN_POSITION as usize + 4] ^= 1;
+        let err = Registry::default()
+            .decrypt_stream(
+                &mut Cursor::new(file),
+                &mut vec![],
+                "ncm",
+                &Default::default(),
+            )
+            .unwrap_err();
+        assert_eq!(err.code(), "EKEY_PARSE");
+    }
+
+    #[test]
+    fn test_fixture() {
+        // `encrypted_file(JSON, b"\xff\xd8cover", SAWTOOTH_FLAC)`
+        let file = include_bytes!("../../fixtures/sawtooth.ncm");
+        let (output, report) = decrypt_all(&Registry::default(), file, "ncm");
+        assert_eq!(output, SAWTOOTH_FLAC);
+        assert_eq!(report.song_id, "1234");
+        assert_eq!(report.metadata.format.as_deref(), Some("flac"));
+        assert_eq!(
+            report.metadata.cover.as_deref(),
+            Some(&b"\xff\xd8cover"[..])
+        );
+    }
+}
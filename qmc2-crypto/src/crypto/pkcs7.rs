@@ -0,0 +1,32 @@
+use super::errors::CryptoError;
+
+/// Length of the PKCS#7 padding at the end of `last`, the last decrypted block.
+pub(super) fn padding_len(last: &[u8]) -> Result<usize, CryptoError> {
+    let padding = last[last.len() - 1] as usize;
+    if padding == 0 || padding > last.len() {
+        return Err(CryptoError::InvalidPadding);
+    }
+    if last[last.len() - padding..]
+        .iter()
+        .any(|&b| b as usize != padding)
+    {
+        return Err(CryptoError::InvalidPadding);
+    }
+    Ok(padding)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_padding_len() {
+        let mut block = [7u8; 16];
+        assert_eq!(padding_len(&block), Ok(7));
+        block[10] = 1;
+        assert_eq!(padding_len(&block), Err(CryptoError::InvalidPadding));
+        assert_eq!(padding_len(&[0u8; 16]), Err(CryptoError::InvalidPadding));
+        assert_eq!(padding_len(&[17u8; 16]), Err(CryptoError::InvalidPadding));
+        assert_eq!(padding_len(&[16u8; 16]), Ok(16));
+    }
+}
@@ -1,16 +1,131 @@
-use super::errors::CryptoError;
+use super::detection::{detect, RECOMMENDED_DETECTION_SIZE};
+use super::errors::DecryptError;
+use super::format::{Cipher, Confidence, Format, Opened, ReadSeek};
 use super::key_dec;
 use super::qmc2_base::QMC2Crypto;
 use super::qmc2_map::QMCStreamMapCrypto;
 use super::qmc2_rc4::QMCStreamRC4Crypto;
+use super::stream::read_trailer;
+use super::trailer::TrailerKind;
 
-pub fn decrypt_factory(ekey: &str) -> Result<Box<dyn QMC2Crypto>, CryptoError> {
-    let key = key_dec::parse_ekey(ekey)?;
+/// Which of the two QMC2 ciphers a key selects.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum CipherKind {
+    Map,
+    RC4,
+}
+
+impl CipherKind {
+    pub fn for_key(key: &[u8]) -> Self {
+        // use RC4 if > 300, otherwise use old xor algorithm.
+        if key.len() > 300 {
+            CipherKind::RC4
+        } else {
+            CipherKind::Map
+        }
+    }
+}
 
-    // use RC4 if > 300, otherwise use old xor algorithm.
-    Ok(if key.len() > 300 {
-        Box::new(QMCStreamRC4Crypto::new(&key))
-    } else {
-        Box::new(QMCStreamMapCrypto::new(&key))
+/// Create the cipher for an already decoded key, which is at least 8 bytes.
+pub fn crypto_from_key(key: &[u8]) -> Result<Box<dyn QMC2Crypto>, DecryptError> {
+    if key.len() < 8 {
+        return Err(DecryptError::InvalidKeyLength(key.len()));
+    }
+    Ok(match CipherKind::for_key(key) {
+        CipherKind::RC4 => Box::new(QMCStreamRC4Crypto::new(key)),
+        CipherKind::Map => Box::new(QMCStreamMapCrypto::new(key)),
     })
 }
+
+pub fn decrypt_factory(ekey: &str) -> Result<Box<dyn QMC2Crypto>, DecryptError> {
+    let key = key_dec::parse_ekey(ekey)?;
+    crypto_from_key(&key)
+}
+
+/// QMC2 files: audio encrypted with one of the ciphers above, followed by a
+/// trailer holding the ekey. Each kind of trailer is its own format.
+pub struct QMC2Format {
+    trailer: TrailerKind,
+    ekey: Option<String>,
+}
+
+impl QMC2Format {
+    pub fn new(trailer: TrailerKind) -> Self {
+        QMC2Format {
+            trailer,
+            ekey: None,
+        }
+    }
+
+    /// Decrypt with `ekey` instead of the one embedded in the file.
+    pub fn with_ekey(trailer: TrailerKind, ekey: &str) -> Self {
+        QMC2Format {
+            trailer,
+            ekey: Some(ekey.into()),
+        }
+    }
+}
+
+impl Format for QMC2Format {
+    fn name(&self) -> &'static str {
+        match self.trailer {
+            TrailerKind::V1 => "qmc2-v1",
+            TrailerKind::QTag => "qmc2-qtag",
+        }
+    }
+
+    fn extensions(&self) -> &'static [(&'static str, &'static str)] {
+        // Some clients append a digit.
+        &[
+            ("mflac", "flac"),
+            ("mflac0", "flac"),
+            ("mflac1", "flac"),
+            ("mgg", "ogg"),
+            ("mgg0", "ogg"),
+            ("mgg1", "ogg"),
+            ("mmp4", "mp4"),
+        ]
+    }
+
+    fn probe(&self, _head: &[u8], tail: &[u8], ext: &str) -> Confidence {
+        let detection_len = std::cmp::min(tail.len(), RECOMMENDED_DETECTION_SIZE);
+        if detect(&tail[tail.len() - detection_len..]).is_err() {
+            return Confidence::No;
+        }
+
+        let known_ext = self
+            .extensions()
+            .iter()
+            .any(|(encrypted, _)| ext == *encrypted);
+        match (self.trailer, tail.ends_with(b"QTag")) {
+            (TrailerKind::QTag, true) => Confidence::Certain,
+            // Any small number at the end of a file looks like a v1 trailer.
+            (TrailerKind::V1, false) if known_ext => Confidence::Likely,
+            (TrailerKind::V1, false) => Confidence::Maybe,
+            _ => Confidence::No,
+        }
+    }
+
+    fn open(&self, file: &mut dyn ReadSeek) -> Result<Opened, DecryptError> {
+        let (detection, audio_len, key) = read_trailer(file, self.ekey.as_deref())?;
+        Ok(Opened {
+            cipher: Cipher::Stream(crypto_from_key(&key)?),
+            audio_range: 0..audio_len,
+            audio_len,
+            song_id: detection.song_id,
+            metadata: Default::default(),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_crypto_from_short_key() {
+        let err = crypto_from_key(&[1; 7]).err().unwrap();
+        assert!(matches!(err, DecryptError::InvalidKeyLength(7)));
+        assert!(crypto_from_key(&[1; 8]).is_ok());
+    }
+}
@@ -1,4 +1,28 @@
-pub trait QMC2Crypto {
+use super::errors::CryptoError;
+
+/// `Send + Sync` so a cipher can be shared between threads, or kept in
+/// objects of bindings that require it.
+pub trait QMC2Crypto: Send + Sync {
     fn get_recommended_block_size(&self) -> usize;
-    fn decrypt(&self, offset: usize, buf: &mut [u8]);
+    /// `offset` is the position of `buf[0]` in the audio data; it is 64-bit so
+    /// files over 4 GiB work on 32-bit targets (e.g. wasm32) too.
+    fn decrypt(&self, offset: u64, buf: &mut [u8]);
+
+    /// Both ciphers XOR the data with a key stream, so this is the same as `decrypt`.
+    fn encrypt(&self, offset: u64, buf: &mut [u8]) {
+        self.decrypt(offset, buf)
+    }
+}
+
+/// A cipher over fixed-size blocks whose plain text is shorter than the
+/// cipher text (e.g. AES with padding), so it cannot be addressed by offset
+/// like `QMC2Crypto`.
+pub trait BlockCrypto: Send + Sync {
+    /// Size of an encrypted block; only the last block of a file can be shorter.
+    fn encrypted_block_size(&self) -> usize;
+    /// Size of every decrypted block but the last.
+    fn decrypted_block_size(&self) -> usize;
+    /// Decrypt a block in place; returns the length of the plain text, which
+    /// is at the start of `block`.
+    fn decrypt_block(&self, block: &mut [u8]) -> Result<usize, CryptoError>;
 }
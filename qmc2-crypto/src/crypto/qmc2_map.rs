@@ -24,13 +24,15 @@ impl QMCStreamMapCrypto {
     }
 
     #[inline]
-    pub(self) fn map_l(&self, offset: usize) -> u8 {
+    pub(self) fn map_l(&self, offset: u64) -> u8 {
         let mut offset_local = offset;
 
         if offset_local > 0x7FFF {
             offset_local %= 0x7FFF;
         }
 
+        // Now at most 0x7FFF, so the square fits even in a 32-bit usize.
+        let offset_local = offset_local as usize;
         let index = (offset_local * offset_local + 71214) % self.key.len();
         QMCStreamMapCrypto::scramble_by_index(self.key[index], index)
     }
@@ -41,10 +43,10 @@ impl QMC2Crypto for QMCStreamMapCrypto {
         RECOMMENDED_BLOCK_SIZE
     }
 
-    fn decrypt(&self, offset: usize, buf: &mut [u8]) {
-        buf.iter_mut().enumerate().for_each(|(i, byte)| {
-            *byte ^= self.map_l(offset + i);
-        });
+    fn decrypt(&self, offset: u64, buf: &mut [u8]) {
+        for (offset, byte) in (offset..).zip(buf.iter_mut()) {
+            *byte ^= self.map_l(offset);
+        }
     }
 }
 
@@ -80,4 +82,13 @@ mod tests {
         crypto.decrypt(0x7FFF - 8, &mut data);
         assert_eq!(data, EXPECTED2);
     }
+
+    #[test]
+    fn map_l_test_beyond_4gib() {
+        // Offsets past 0x7FFF wrap around, and 0x7FFF * 0x20004 > 2^32.
+        let crypto = QMCStreamMapCrypto::new(&KEY);
+        let mut data = [0u8; 16];
+        crypto.decrypt(0x7FFF * 0x20004, &mut data);
+        assert_eq!(data, EXPECTED1);
+    }
 }
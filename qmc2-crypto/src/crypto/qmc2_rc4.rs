@@ -1,11 +1,11 @@
 use super::qmc2_base::QMC2Crypto;
 
-const FIRST_SEGMENT_SIZE: usize = 0x80;
-const OTHER_SEGMENT_SIZE: usize = 0x1400;
+const FIRST_SEGMENT_SIZE: u64 = 0x80;
+const OTHER_SEGMENT_SIZE: u64 = 0x1400;
 
 /// Recommends 2.5M block, aligns to the segment size.
 const RECOMMENDED_BLOCK_SIZE: usize = (1024 * 1024) * 5 / 2;
-static_assertions::const_assert_eq!(RECOMMENDED_BLOCK_SIZE % OTHER_SEGMENT_SIZE, 0);
+static_assertions::const_assert_eq!(RECOMMENDED_BLOCK_SIZE as u64 % OTHER_SEGMENT_SIZE, 0);
 
 pub struct QMCStreamRC4Crypto {
     /// RC4 seed box
@@ -18,16 +18,16 @@ pub struct QMCStreamRC4Crypto {
 
 impl QMCStreamRC4Crypto {
     #[inline]
-    pub(self) fn calc_segment_key(&self, id: usize, seed: u8) -> usize {
+    pub(self) fn calc_segment_key(&self, id: u64, seed: u8) -> u64 {
         let dividend = f64::from(self.hash);
-        let divisor = ((id + 1) * usize::from(seed)) as f64;
+        let divisor = ((id + 1) * u64::from(seed)) as f64;
         let key = dividend / divisor * 100.0;
-        key as u64 as usize
+        key as u64
     }
 
     #[inline]
     /// Get next rc4 xor byte value
-    pub(self) fn rc4_derive(n: usize, s: &mut Vec<u8>, j: &mut usize, k: &mut usize) -> u8 {
+    pub(self) fn rc4_derive(n: usize, s: &mut [u8], j: &mut usize, k: &mut usize) -> u8 {
         *j = (*j + 1) % n;
         *k = (usize::from(s[*j]) + *k) % n;
 
@@ -39,27 +39,24 @@ impl QMCStreamRC4Crypto {
 
     #[inline]
     /// Encode first segment
-    pub(self) fn encode_first_segment(&self, offset: usize, buf: &mut [u8]) {
+    pub(self) fn encode_first_segment(&self, offset: u64, buf: &mut [u8]) {
         let n = self.rc4_key.len();
-        let mut offset = offset;
-        for b in buf.iter_mut() {
-            let key1 = self.rc4_key[offset % n];
+        for (offset, b) in (offset..).zip(buf.iter_mut()) {
+            let key1 = self.rc4_key[offset as usize % n];
             let key2 = self.calc_segment_key(offset, key1);
-            *b ^= self.rc4_key[key2 % n];
-
-            offset += 1;
+            *b ^= self.rc4_key[(key2 % n as u64) as usize];
         }
     }
 
     #[inline]
     /// Encode segments (other than the first one)
-    pub(self) fn encode_other_segment(&self, offset: usize, buf: &mut [u8]) {
+    pub(self) fn encode_other_segment(&self, offset: u64, buf: &mut [u8]) {
         // segment_id: 0~511 (inclusive)
         let seg_id = offset / OTHER_SEGMENT_SIZE;
-        let seg_id_small = seg_id & 0x1FF;
+        let seg_id_small = (seg_id & 0x1FF) as usize;
 
-        let mut discard_count = self.calc_segment_key(seg_id, self.rc4_key[seg_id_small]) & 0x1FF;
-        discard_count += offset % OTHER_SEGMENT_SIZE;
+        let discard_count = (self.calc_segment_key(seg_id, self.rc4_key[seg_id_small]) & 0x1FF)
+            + offset % OTHER_SEGMENT_SIZE;
 
         let n = self.rc4_key.len();
         let mut s = self.s.clone();
@@ -127,35 +124,36 @@ impl QMC2Crypto for QMCStreamRC4Crypto {
         RECOMMENDED_BLOCK_SIZE
     }
 
-    fn decrypt(&self, offset: usize, buf: &mut [u8]) {
+    fn decrypt(&self, offset: u64, buf: &mut [u8]) {
         let mut offset = offset;
         let mut len = buf.len();
         let mut i = 0usize;
 
         // First segment have a different algorithm.
         if offset < FIRST_SEGMENT_SIZE {
-            let len_processed = std::cmp::min(len, FIRST_SEGMENT_SIZE - offset);
+            let len_processed = std::cmp::min(len, (FIRST_SEGMENT_SIZE - offset) as usize);
             self.encode_first_segment(offset, &mut buf[i..i + len_processed]);
             i += len_processed;
             len -= len_processed;
-            offset += len_processed;
+            offset += len_processed as u64;
         }
 
         // Align a segment
         let to_align = offset % OTHER_SEGMENT_SIZE;
         if to_align != 0 {
-            let len_processed = std::cmp::min(len, OTHER_SEGMENT_SIZE - to_align);
+            let len_processed = std::cmp::min(len, (OTHER_SEGMENT_SIZE - to_align) as usize);
             self.encode_other_segment(offset, &mut buf[i..i + len_processed]);
             i += len_processed;
             len -= len_processed;
-            offset += len_processed;
+            offset += len_processed as u64;
         }
 
         // Process segments
-        while len > OTHER_SEGMENT_SIZE {
-            self.encode_other_segment(offset, &mut buf[i..i + OTHER_SEGMENT_SIZE]);
-            i += OTHER_SEGMENT_SIZE;
-            len -= OTHER_SEGMENT_SIZE;
+        let segment_size = OTHER_SEGMENT_SIZE as usize;
+        while len > segment_size {
+            self.encode_other_segment(offset, &mut buf[i..i + segment_size]);
+            i += segment_size;
+            len -= segment_size;
             offset += OTHER_SEGMENT_SIZE;
         }
 
@@ -264,7 +262,7 @@ mod tests {
             *p = i as u8
         }
         let crypto = QMCStreamRC4Crypto::new(&rc4_key);
-        let mut data = vec![0u8; OTHER_SEGMENT_SIZE + 1];
+        let mut data = vec![0u8; OTHER_SEGMENT_SIZE as usize + 1];
         crypto.decrypt(OTHER_SEGMENT_SIZE, &mut data);
         // Only checks for the first 16 bytes
         assert_eq!(
@@ -275,4 +273,34 @@ mod tests {
             ]
         );
     }
+
+    #[test]
+    fn test_decrypt_beyond_4gib() {
+        let mut rc4_key = [0u8; 512];
+        for (i, p) in rc4_key.iter_mut().enumerate() {
+            *p = i as u8
+        }
+        let crypto = QMCStreamRC4Crypto::new(&rc4_key);
+
+        // Segment 0x100001 is past 2^32; truncating the offset would pick another segment.
+        let seg_id = 0x100001u64;
+        let offset = OTHER_SEGMENT_SIZE * seg_id + 8;
+        let mut data = [0u8; 16];
+        crypto.decrypt(offset, &mut data);
+
+        // Every segment takes the same key stream after skipping some of it, so
+        // a low segment that skips less gives the same bytes a little further in.
+        let discard_count =
+            |id: u64| crypto.calc_segment_key(id, rc4_key[(id & 0x1FF) as usize]) & 0x1FF;
+        let skip = discard_count(seg_id);
+        let low_id = (1..0x200).find(|&id| discard_count(id) <= skip).unwrap();
+        let mut expected = [0u8; 16];
+        let low_offset = OTHER_SEGMENT_SIZE * low_id + skip - discard_count(low_id) + 8;
+        crypto.decrypt(low_offset, &mut expected);
+        assert_eq!(data, expected);
+
+        let mut truncated = [0u8; 16];
+        crypto.decrypt(offset as u32 as u64, &mut truncated);
+        assert_ne!(data, truncated);
+    }
 }
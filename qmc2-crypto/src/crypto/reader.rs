@@ -0,0 +1,233 @@
+use std::io::{self, Read, Seek, SeekFrom};
+use std::ops::Range;
+
+use super::detection::Detection;
+use super::errors::DecryptError;
+use super::format::{Cipher, Registry};
+use super::qmc2::crypto_from_key;
+use super::qmc2_base::QMC2Crypto;
+use super::stream::read_trailer;
+
+/// Reads the decrypted audio of a QMC2 file, seeking as needed, so it can be
+/// handed to anything that takes `Read + Seek` (tag readers, decoders...).
+///
+/// Positions are within the audio data; the trailer is not visible.
+pub struct QMC2Reader<R> {
+    inner: R,
+    crypto: Box<dyn QMC2Crypto>,
+    /// Only for files opened by their QMC2 trailer.
+    detection: Option<Detection>,
+    song_id: String,
+    /// Where the audio data starts in `inner`.
+    audio_start: u64,
+    audio_len: u64,
+    position: u64,
+}
+
+impl<R: Read + Seek> QMC2Reader<R> {
+    /// Read the trailer of `inner` and start at the beginning of the audio.
+    pub fn new(inner: R) -> Result<Self, DecryptError> {
+        QMC2Reader::with_ekey(inner, None)
+    }
+
+    /// Like `new`, but with `ekey` instead of the one embedded in the file.
+    pub fn with_ekey(mut inner: R, ekey: Option<&str>) -> Result<Self, DecryptError> {
+        let (detection, audio_len, key) = read_trailer(&mut inner, ekey)?;
+        inner.seek(SeekFrom::Start(0))?;
+
+        Ok(QMC2Reader {
+            inner,
+            crypto: crypto_from_key(&key)?,
+            song_id: detection.song_id.clone(),
+            detection: Some(detection),
+            audio_start: 0,
+            audio_len,
+            position: 0,
+        })
+    }
+
+    /// Open any format of `registry` that can be read from any offset, such
+    /// as one with a header instead of a trailer.
+    ///
+    /// There is no `detection()` then, even for QMC2 files.
+    pub fn open(mut inner: R, registry: &Registry, ext: &str) -> Result<Self, DecryptError> {
+        let (_, opened) = registry.open(&mut inner, ext)?;
+        let Cipher::Stream(crypto) = opened.cipher else {
+            return Err(DecryptError::NotSeekable);
+        };
+        let range = opened.audio_range;
+        inner.seek(SeekFrom::Start(range.start))?;
+
+        Ok(QMC2Reader {
+            inner,
+            crypto,
+            detection: None,
+            song_id: opened.song_id,
+            audio_start: range.start,
+            audio_len: opened.audio_len,
+            position: 0,
+        })
+    }
+
+    /// The trailer the key was found with, unless opened by `open`.
+    pub fn detection(&self) -> Option<&Detection> {
+        self.detection.as_ref()
+    }
+
+    /// Empty if the file does not say.
+    pub fn song_id(&self) -> &str {
+        &self.song_id
+    }
+
+    /// Where the audio data is in the wrapped file.
+    pub fn audio_range(&self) -> Range<u64> {
+        self.audio_start..self.audio_start + self.audio_len
+    }
+
+    /// Size of the audio data, i.e. the file without its trailer or header.
+    pub fn audio_len(&self) -> u64 {
+        self.audio_len
+    }
+
+    pub fn into_inner(self) -> R {
+        self.inner
+    }
+}
+
+impl<R: Read + Seek> Read for QMC2Reader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let audio_left = self.audio_len.saturating_sub(self.position);
+        let len = std::cmp::min(buf.len() as u64, audio_left) as usize;
+        if len == 0 {
+            return Ok(0);
+        }
+
+        let n = self.inner.read(&mut buf[..len])?;
+        self.crypto.decrypt(self.position, &mut buf[..n]);
+        self.position += n as u64;
+        Ok(n)
+    }
+}
+
+impl<R: Read + Seek> Seek for QMC2Reader<R> {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        let position = match pos {
+            SeekFrom::Start(offset) => Some(offset),
+            SeekFrom::End(offset) => self.audio_len.checked_add_signed(offset),
+            SeekFrom::Current(offset) => self.position.checked_add_signed(offset),
+        }
+        .ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "invalid seek to a negative or overflowing position",
+            )
+        })?;
+
+        // Like a file, seeking past the end is allowed; reads there return nothing.
+        let inner_position = self.audio_start.checked_add(position).ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "invalid seek to an overflowing position",
+            )
+        })?;
+        self.inner.seek(SeekFrom::Start(inner_position))?;
+        self.position = position;
+        Ok(position)
+    }
+}
+
+/// Lets symphonia probe and decode encrypted files directly:
+///
+/// ```ignore
+/// let reader = QMC2Reader::new(File::open("song.mflac")?)?;
+/// let stream = MediaSourceStream::new(Box::new(reader), Default::default());
+/// ```
+#[cfg(feature = "symphonia")]
+impl<R: Read + Seek + Send + Sync> symphonia_core::io::MediaSource for QMC2Reader<R> {
+    fn is_seekable(&self) -> bool {
+        true
+    }
+
+    fn byte_len(&self) -> Option<u64> {
+        Some(self.audio_len)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::crypto::test_util::{qtag_file, test_audio, test_key, SONG_ID};
+    use std::io::Cursor;
+
+    #[test]
+    fn test_read_and_seek() {
+        let audio = test_audio(b"fLaC", 30000);
+        let file = qtag_file(&audio, &test_key(512));
+        let mut reader = QMC2Reader::new(Cursor::new(file)).unwrap();
+        assert_eq!(reader.audio_len(), 30000);
+        assert_eq!(reader.song_id(), SONG_ID);
+        assert_eq!(reader.detection().unwrap().song_id, SONG_ID);
+
+        let mut all = vec![];
+        reader.read_to_end(&mut all).unwrap();
+        assert_eq!(all, audio);
+
+        assert_eq!(reader.seek(SeekFrom::End(-100)).unwrap(), 29900);
+        let mut buf = vec![];
+        reader.read_to_end(&mut buf).unwrap();
+        assert_eq!(buf, &audio[29900..]);
+
+        reader.seek(SeekFrom::Start(5000)).unwrap();
+        reader.seek(SeekFrom::Current(-10)).unwrap();
+        let mut buf = [0u8; 20];
+        reader.read_exact(&mut buf).unwrap();
+        assert_eq!(buf, &audio[4990..5010]);
+
+        assert!(reader.seek(SeekFrom::Current(-10000)).is_err());
+        reader.seek(SeekFrom::Start(40000)).unwrap();
+        assert_eq!(reader.read(&mut buf).unwrap(), 0);
+    }
+
+    #[cfg(feature = "symphonia")]
+    #[test]
+    fn test_symphonia_decode() {
+        use symphonia::core::audio::SampleBuffer;
+        use symphonia::core::errors::Error;
+        use symphonia::core::io::MediaSourceStream;
+        use symphonia::core::probe::Hint;
+
+        // 2 frames of 4096 16-bit mono samples, stored verbatim, encrypted with RC4.
+        let file = Cursor::new(&include_bytes!("../../fixtures/sawtooth.mflac")[..]);
+        let reader = QMC2Reader::new(file).unwrap();
+        let stream = MediaSourceStream::new(Box::new(reader), Default::default());
+
+        let mut hint = Hint::new();
+        hint.with_extension("flac");
+        let probed = symphonia::default::get_probe()
+            .format(&hint, stream, &Default::default(), &Default::default())
+            .unwrap();
+        let mut format = probed.format;
+        let track = format.default_track().unwrap();
+        assert_eq!(track.codec_params.sample_rate, Some(44100));
+        assert_eq!(track.codec_params.n_frames, Some(8192));
+
+        let mut decoder = symphonia::default::get_codecs()
+            .make(&track.codec_params, &Default::default())
+            .unwrap();
+        let mut samples = vec![];
+        loop {
+            let packet = match format.next_packet() {
+                Ok(packet) => packet,
+                Err(Error::IoError(err)) if err.kind() == io::ErrorKind::UnexpectedEof => break,
+                Err(err) => panic!("{}", err),
+            };
+            let decoded = decoder.decode(&packet).unwrap();
+            let mut buffer = SampleBuffer::<i16>::new(decoded.capacity() as u64, *decoded.spec());
+            buffer.copy_interleaved_ref(decoded);
+            samples.extend_from_slice(buffer.samples());
+        }
+
+        let expected: Vec<i16> = (0..8192).map(|i| ((i * 37) % 2000 - 1000) as i16).collect();
+        assert_eq!(samples, expected);
+    }
+}
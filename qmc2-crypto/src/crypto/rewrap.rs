@@ -0,0 +1,185 @@
+use std::io::{Read, Seek, SeekFrom, Write};
+
+use super::detection::{Detection, MAX_V1_EKEY_LEN};
+use super::errors::DecryptError;
+use super::key_dec::generate_ekey;
+use super::qmc2::{crypto_from_key, CipherKind};
+use super::stream::read_trailer;
+use super::trailer::{build_qtag_trailer, build_v1_trailer, TrailerKind};
+
+#[derive(Default)]
+pub struct RewrapOptions<'a> {
+    /// Decrypt with this ekey instead of the one embedded in the file.
+    pub ekey: Option<&'a str>,
+    /// Re-encrypt with this key (at least 8 bytes); keeps the current one if unset.
+    pub new_key: Option<&'a [u8]>,
+    /// Song id for the new trailer; keeps the current one if unset.
+    /// Dropped with `TrailerKind::V1`, which cannot hold one.
+    pub song_id: Option<&'a str>,
+    pub trailer: TrailerKind,
+}
+
+#[derive(Debug)]
+pub struct RewrapReport {
+    /// What the trailer of the input said.
+    pub detection: Detection,
+    /// Cipher of the output.
+    pub cipher: CipherKind,
+    /// The ekey stored in the new trailer.
+    pub ekey: String,
+    /// Size of the audio data, the same in the input and the output.
+    pub audio_len: u64,
+    pub bytes_written: u64,
+}
+
+/// Copy a QMC2 file with a new trailer, re-encrypting its audio under
+/// `options.new_key` in the same pass if one is given.
+pub fn rewrap_stream<R, W>(
+    reader: &mut R,
+    writer: &mut W,
+    options: &RewrapOptions,
+) -> Result<RewrapReport, DecryptError>
+where
+    R: Read + Seek + ?Sized,
+    W: Write + ?Sized,
+{
+    let (detection, audio_len, key) = read_trailer(reader, options.ekey)?;
+    let new_key = options.new_key.unwrap_or(&key);
+    if new_key.len() < 8 {
+        return Err(DecryptError::InvalidKeyLength(new_key.len()));
+    }
+
+    let ekey = generate_ekey(new_key);
+    let song_id = options.song_id.unwrap_or(&detection.song_id);
+    let trailer = match options.trailer {
+        TrailerKind::V1 if ekey.len() > MAX_V1_EKEY_LEN => {
+            return Err(DecryptError::EKeyTooLongForV1(ekey.len()));
+        }
+        TrailerKind::V1 => build_v1_trailer(&ekey),
+        TrailerKind::QTag => build_qtag_trailer(&ekey, song_id),
+    };
+
+    // Same key: the audio can be copied as is.
+    let recrypt = match options.new_key {
+        Some(new_key) if *new_key != *key => {
+            Some((crypto_from_key(&key)?, crypto_from_key(new_key)?))
+        }
+        _ => None,
+    };
+
+    let mut buf = vec![0u8; std::cmp::min(audio_len, 0x10000) as usize];
+    let mut offset = 0;
+    reader.seek(SeekFrom::Start(0))?;
+    while offset < audio_len {
+        let read_size = std::cmp::min(audio_len - offset, buf.len() as u64) as usize;
+        let block = &mut buf[..read_size];
+        reader.read_exact(block)?;
+        if let Some((old_crypto, new_crypto)) = &recrypt {
+            old_crypto.decrypt(offset, block);
+            new_crypto.encrypt(offset, block);
+        }
+        writer.write_all(block)?;
+        offset += read_size as u64;
+    }
+    writer.write_all(&trailer)?;
+
+    Ok(RewrapReport {
+        detection,
+        cipher: CipherKind::for_key(new_key),
+        ekey,
+        audio_len,
+        bytes_written: audio_len + trailer.len() as u64,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::crypto::reader::QMC2Reader;
+    use crate::crypto::test_util::{test_audio, test_key};
+    use std::io::Cursor;
+
+    fn encrypted_file(key: &[u8], audio: &[u8], trailer: &[u8]) -> Vec<u8> {
+        let mut data = audio.to_vec();
+        crypto_from_key(key).unwrap().encrypt(0, &mut data);
+        data.extend(trailer);
+        data
+    }
+
+    fn read_audio(file: Vec<u8>) -> (Vec<u8>, String) {
+        let mut reader = QMC2Reader::new(Cursor::new(file)).unwrap();
+        let mut audio = vec![];
+        reader.read_to_end(&mut audio).unwrap();
+        (audio, reader.song_id().to_string())
+    }
+
+    #[test]
+    fn test_rewrap_to_qtag_with_new_key() {
+        let audio = test_audio(b"fLaC", 100000);
+        let old_key = test_key(256);
+        let new_key: Vec<u8> = (0..512).map(|i| (i * 5 + 3) as u8 | 1).collect();
+        let input = encrypted_file(
+            &old_key,
+            &audio,
+            &build_v1_trailer(&generate_ekey(&old_key)),
+        );
+
+        let mut output = vec![];
+        let options = RewrapOptions {
+            new_key: Some(&new_key),
+            song_id: Some("4242"),
+            ..Default::default()
+        };
+        let report = rewrap_stream(&mut Cursor::new(input), &mut output, &options).unwrap();
+        assert_eq!(report.cipher, CipherKind::RC4);
+        assert_eq!(report.detection.song_id, "");
+        assert_eq!(report.bytes_written, output.len() as u64);
+
+        let expected = encrypted_file(&new_key, &audio, &build_qtag_trailer(&report.ekey, "4242"));
+        assert_eq!(output, expected);
+        assert_eq!(read_audio(output), (audio, "4242".into()));
+    }
+
+    #[test]
+    fn test_rewrap_to_v1_keeps_audio() {
+        let audio = test_audio(b"fLaC", 5000);
+        let key = test_key(256);
+        let input = encrypted_file(&key, &audio, &build_qtag_trailer(&generate_ekey(&key), "1"));
+
+        let mut output = vec![];
+        let options = RewrapOptions {
+            trailer: TrailerKind::V1,
+            ..Default::default()
+        };
+        let report = rewrap_stream(&mut Cursor::new(&input), &mut output, &options).unwrap();
+        assert_eq!(report.detection.song_id, "1");
+        assert_eq!(output[..5000], input[..5000]);
+        assert_eq!(read_audio(output), (audio, "".into()));
+    }
+
+    #[test]
+    fn test_rewrap_refuses_bad_keys() {
+        let key = test_key(512);
+        let input = encrypted_file(
+            &key,
+            &[0u8; 100],
+            &build_qtag_trailer(&generate_ekey(&key), "1"),
+        );
+
+        let options = RewrapOptions {
+            new_key: Some(&[1, 2, 3]),
+            ..Default::default()
+        };
+        let err = rewrap_stream(&mut Cursor::new(&input), &mut vec![], &options).unwrap_err();
+        assert_eq!(err.code(), "INVALID_KEY_LENGTH");
+
+        let long_key = [1u8; 1024];
+        let options = RewrapOptions {
+            new_key: Some(&long_key),
+            trailer: TrailerKind::V1,
+            ..Default::default()
+        };
+        let err = rewrap_stream(&mut Cursor::new(&input), &mut vec![], &options).unwrap_err();
+        assert_eq!(err.code(), "EKEY_TOO_LONG");
+    }
+}
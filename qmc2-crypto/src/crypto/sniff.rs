@@ -0,0 +1,80 @@
+/// How many bytes of decrypted audio `AudioFormat::sniff` looks at.
+pub const SNIFF_SIZE: usize = 16;
+
+/// Container format of the decrypted audio, guessed from its first bytes.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum AudioFormat {
+    Flac,
+    Ogg,
+    Mp3,
+    M4a,
+    Wav,
+    Unknown,
+}
+
+impl AudioFormat {
+    pub fn sniff(head: &[u8]) -> Self {
+        if head.starts_with(b"fLaC") {
+            AudioFormat::Flac
+        } else if head.starts_with(b"OggS") {
+            AudioFormat::Ogg
+        } else if head.starts_with(b"ID3")
+            || (head.len() >= 2 && head[0] == 0xFF && head[1] & 0xE0 == 0xE0)
+        {
+            // ID3 tag, or straight into an MPEG frame sync.
+            AudioFormat::Mp3
+        } else if head.len() >= 8 && &head[4..8] == b"ftyp" {
+            AudioFormat::M4a
+        } else if head.len() >= 12 && head.starts_with(b"RIFF") && &head[8..12] == b"WAVE" {
+            AudioFormat::Wav
+        } else {
+            AudioFormat::Unknown
+        }
+    }
+
+    /// File extension, without the leading dot.
+    pub fn extension(self) -> &'static str {
+        match self {
+            AudioFormat::Flac => "flac",
+            AudioFormat::Ogg => "ogg",
+            AudioFormat::Mp3 => "mp3",
+            AudioFormat::M4a => "m4a",
+            AudioFormat::Wav => "wav",
+            AudioFormat::Unknown => "bin",
+        }
+    }
+
+    pub fn mime_type(self) -> &'static str {
+        match self {
+            AudioFormat::Flac => "audio/flac",
+            AudioFormat::Ogg => "audio/ogg",
+            AudioFormat::Mp3 => "audio/mpeg",
+            AudioFormat::M4a => "audio/mp4",
+            AudioFormat::Wav => "audio/wav",
+            AudioFormat::Unknown => "application/octet-stream",
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_sniff() {
+        assert_eq!(AudioFormat::sniff(b"fLaC\0\0\0\x22"), AudioFormat::Flac);
+        assert_eq!(AudioFormat::sniff(b"OggS\0\x02"), AudioFormat::Ogg);
+        assert_eq!(AudioFormat::sniff(b"ID3\x04\0"), AudioFormat::Mp3);
+        assert_eq!(
+            AudioFormat::sniff(&[0xFF, 0xFB, 0x90, 0x64]),
+            AudioFormat::Mp3
+        );
+        assert_eq!(AudioFormat::sniff(b"\0\0\0\x20ftypM4A "), AudioFormat::M4a);
+        assert_eq!(
+            AudioFormat::sniff(b"RIFF\x24\0\0\0WAVEfmt "),
+            AudioFormat::Wav
+        );
+        assert_eq!(AudioFormat::sniff(b"RIFF"), AudioFormat::Unknown);
+        assert_eq!(AudioFormat::sniff(b""), AudioFormat::Unknown);
+    }
+}
@@ -0,0 +1,509 @@
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::ops::Range;
+use std::sync::atomic::{AtomicBool, Ordering};
+
+use super::detection::{self, Detection, RECOMMENDED_DETECTION_SIZE};
+use super::errors::{DecryptError, DetectionError};
+use super::key_dec;
+use super::qmc2::{crypto_from_key, decrypt_factory, CipherKind};
+use super::qmc2_base::{BlockCrypto, QMC2Crypto};
+use super::sniff::{AudioFormat, SNIFF_SIZE};
+
+/// How far a decryption has got, reported after every block.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Progress {
+    /// Offset of the next byte to decrypt.
+    pub position: u64,
+    /// Offset where the audio data ends.
+    pub total: u64,
+}
+
+#[derive(Default)]
+pub struct Options<'a> {
+    /// Bytes to decrypt at a time; defaults to what the cipher recommends.
+    pub block_size: Option<usize>,
+    /// Use this ekey instead of the one embedded in the file.
+    pub ekey: Option<&'a str>,
+    /// Position in the audio data to start from, e.g. to resume an earlier run.
+    /// Only the data from there on is written.
+    pub start: u64,
+    /// Called once before the first block, then after every block.
+    pub on_progress: Option<&'a dyn Fn(Progress)>,
+    /// Checked before every block; set it to stop with `DecryptError::Cancelled`.
+    pub cancel: Option<&'a AtomicBool>,
+}
+
+#[derive(Debug)]
+pub struct Report {
+    pub detection: Detection,
+    pub cipher: CipherKind,
+    /// Guessed from the first decrypted bytes.
+    pub format: AudioFormat,
+    /// Size of the audio data, i.e. the file without its trailer.
+    pub audio_len: u64,
+    pub bytes_written: u64,
+}
+
+/// How much of the end of a file `parse_tail` should get, enough for any
+/// known trailer (including its ekey).
+pub const RECOMMENDED_TAIL_SIZE: usize = 0x1000;
+
+/// What the end of a file says about it, found without seeking.
+#[derive(Debug)]
+pub struct TailInfo {
+    pub detection: Detection,
+    /// Size of the audio data, i.e. the file without its trailer.
+    pub audio_len: u64,
+    pub ekey: String,
+}
+
+/// Read the trailer from the last bytes of a file, `file_len` bytes in total.
+///
+/// `tail` has to hold the whole trailer; `RECOMMENDED_TAIL_SIZE` bytes (or
+/// the whole file, if smaller) is enough.
+pub fn parse_tail(file_len: u64, tail: &[u8]) -> Result<TailInfo, DecryptError> {
+    if tail.len() as u64 > file_len {
+        return Err(DetectionError::BufferTooSmall.into());
+    }
+
+    let detection_len = std::cmp::min(tail.len(), RECOMMENDED_DETECTION_SIZE);
+    let detection_start = tail.len() - detection_len;
+    let detection = detection::detect(&tail[detection_start..])?;
+
+    // Positions in the detection are relative to the detection buffer.
+    let ekey_start = detection_start as i64 + detection.ekey_position;
+    let ekey_end = ekey_start + detection.ekey_len as i64;
+    if ekey_start < 0 || ekey_end > tail.len() as i64 {
+        return Err(DetectionError::BufferTooSmall.into());
+    }
+    let ekey = std::str::from_utf8(&tail[ekey_start as usize..ekey_end as usize])
+        .map_err(|_| DecryptError::EKeyNotUtf8)?;
+
+    let tail_start = file_len - tail.len() as u64;
+    let audio_len = tail_start as i64 + detection_start as i64 + detection.eof_position;
+
+    Ok(TailInfo {
+        audio_len: audio_len as u64,
+        ekey: ekey.into(),
+        detection,
+    })
+}
+
+/// Decrypts a file fed front to back in chunks of any size, e.g. from a
+/// network or a WHATWG `ReadableStream`, without seeking.
+pub struct StreamDecryptor {
+    crypto: Box<dyn QMC2Crypto>,
+    position: u64,
+    audio_len: u64,
+}
+
+impl StreamDecryptor {
+    /// Anything fed past `audio_len` (the trailer) is dropped.
+    pub fn new(crypto: Box<dyn QMC2Crypto>, audio_len: u64) -> Self {
+        StreamDecryptor {
+            crypto,
+            position: 0,
+            audio_len,
+        }
+    }
+
+    pub fn from_tail(tail: &TailInfo) -> Result<Self, DecryptError> {
+        let crypto = decrypt_factory(&tail.ekey)?;
+        Ok(StreamDecryptor::new(crypto, tail.audio_len))
+    }
+
+    /// Decrypt the next chunk in place.
+    ///
+    /// Returns how many bytes at the start of `buf` are audio; the rest
+    /// belongs to the trailer and should be discarded.
+    pub fn decrypt_chunk(&mut self, buf: &mut [u8]) -> usize {
+        let audio_left = self.audio_len.saturating_sub(self.position);
+        let len = std::cmp::min(buf.len() as u64, audio_left) as usize;
+
+        self.crypto.decrypt(self.position, &mut buf[..len]);
+        self.position += buf.len() as u64;
+        len
+    }
+
+    /// Bytes fed so far.
+    pub fn position(&self) -> u64 {
+        self.position
+    }
+
+    pub fn audio_len(&self) -> u64 {
+        self.audio_len
+    }
+
+    /// Whether all of the audio data has been decrypted.
+    pub fn is_done(&self) -> bool {
+        self.position >= self.audio_len
+    }
+}
+
+/// Where the audio ends and where the ekey starts in a file, for a
+/// `detection` made from its bytes from `detection_start` on.
+pub(crate) fn trailer_positions(
+    detection_start: u64,
+    detection: &Detection,
+) -> Result<(u64, u64), DetectionError> {
+    // Positions in the detection are relative to the detection buffer.
+    let to_absolute = |position: i64| {
+        (detection_start as i64)
+            .checked_add(position)
+            .and_then(|position| u64::try_from(position).ok())
+            .ok_or(DetectionError::PositionOutOfRange)
+    };
+    Ok((
+        to_absolute(detection.eof_position)?,
+        to_absolute(detection.ekey_position)?,
+    ))
+}
+
+/// Find the trailer of a seekable file and decode its key (or `ekey`, if given).
+///
+/// Returns the detection, the size of the audio data and the key.
+pub(crate) fn read_trailer<R>(
+    reader: &mut R,
+    ekey: Option<&str>,
+) -> Result<(Detection, u64, Box<[u8]>), DecryptError>
+where
+    R: Read + Seek + ?Sized,
+{
+    let file_len = reader.seek(SeekFrom::End(0))?;
+    let detection_len = std::cmp::min(file_len, RECOMMENDED_DETECTION_SIZE as u64);
+    let detection_start = file_len - detection_len;
+
+    let mut detection_buf = vec![0u8; detection_len as usize];
+    reader.seek(SeekFrom::Start(detection_start))?;
+    reader.read_exact(&mut detection_buf)?;
+    let detection = detection::detect(&detection_buf)?;
+
+    let (audio_len, ekey_start) = trailer_positions(detection_start, &detection)?;
+
+    let key = match ekey {
+        Some(ekey) => key_dec::parse_ekey(ekey)?,
+        None => {
+            let mut ekey_buf = vec![0u8; detection.ekey_len];
+            reader.seek(SeekFrom::Start(ekey_start))?;
+            reader.read_exact(&mut ekey_buf)?;
+            let ekey = std::str::from_utf8(&ekey_buf).map_err(|_| DecryptError::EKeyNotUtf8)?;
+            key_dec::parse_ekey(ekey)?
+        }
+    };
+
+    Ok((detection, audio_len, key))
+}
+
+/// Decrypt a whole QMC2 file: find its trailer, extract the key and decrypt
+/// the audio data into `writer`.
+pub fn decrypt_stream<R, W>(
+    reader: &mut R,
+    writer: &mut W,
+    options: &Options,
+) -> Result<Report, DecryptError>
+where
+    R: Read + Seek + ?Sized,
+    W: Write + ?Sized,
+{
+    let (detection, audio_len, key) = read_trailer(reader, options.ekey)?;
+    let cipher = CipherKind::for_key(&key);
+    let crypto = crypto_from_key(&key)?;
+    let (format, bytes_written) =
+        decrypt_range(reader, writer, &*crypto, &(0..audio_len), options)?;
+
+    Ok(Report {
+        detection,
+        cipher,
+        format,
+        audio_len,
+        bytes_written,
+    })
+}
+
+/// Decrypt the audio data found at `range` in `reader` into `writer`.
+///
+/// Returns the sniffed format and the number of bytes written.
+pub(crate) fn decrypt_range<R, W>(
+    reader: &mut R,
+    writer: &mut W,
+    crypto: &dyn QMC2Crypto,
+    range: &Range<u64>,
+    options: &Options,
+) -> Result<(AudioFormat, u64), DecryptError>
+where
+    R: Read + Seek + ?Sized,
+    W: Write + ?Sized,
+{
+    let audio_len = range.end - range.start;
+    if options.start > audio_len {
+        return Err(DecryptError::StartOutOfRange {
+            start: options.start,
+            audio_len,
+        });
+    }
+
+    let mut head = vec![0u8; std::cmp::min(audio_len, SNIFF_SIZE as u64) as usize];
+    reader.seek(SeekFrom::Start(range.start))?;
+    reader.read_exact(&mut head)?;
+    crypto.decrypt(0, &mut head);
+    let format = AudioFormat::sniff(&head);
+
+    let block_size = options
+        .block_size
+        .unwrap_or_else(|| crypto.get_recommended_block_size())
+        .max(1);
+    let mut offset = options.start;
+    let mut bytes_to_decrypt = audio_len - options.start;
+    let mut buf = vec![0u8; std::cmp::min(bytes_to_decrypt, block_size as u64) as usize];
+
+    let report_progress = |offset: u64| {
+        if let Some(on_progress) = options.on_progress {
+            on_progress(Progress {
+                position: offset,
+                total: audio_len,
+            });
+        }
+    };
+
+    reader.seek(SeekFrom::Start(range.start + options.start))?;
+    report_progress(offset);
+    while bytes_to_decrypt > 0 {
+        if options
+            .cancel
+            .is_some_and(|cancel| cancel.load(Ordering::Relaxed))
+        {
+            return Err(DecryptError::Cancelled);
+        }
+
+        // Don't read over the audio data...
+        let read_size = std::cmp::min(bytes_to_decrypt, buf.len() as u64) as usize;
+        reader.read_exact(&mut buf[0..read_size])?;
+        crypto.decrypt(offset, &mut buf[0..read_size]);
+        writer.write_all(&buf[0..read_size])?;
+
+        // Keep track of the progress.
+        bytes_to_decrypt -= read_size as u64;
+        offset += read_size as u64;
+        report_progress(offset);
+    }
+
+    Ok((format, audio_len - options.start))
+}
+
+/// Like `decrypt_range`, for audio data encrypted in blocks that decrypt to
+/// `audio_len` bytes in total.
+pub(crate) fn decrypt_blocks<R, W>(
+    reader: &mut R,
+    writer: &mut W,
+    crypto: &dyn BlockCrypto,
+    range: &Range<u64>,
+    audio_len: u64,
+    options: &Options,
+) -> Result<(AudioFormat, u64), DecryptError>
+where
+    R: Read + Seek + ?Sized,
+    W: Write + ?Sized,
+{
+    if options.start > audio_len {
+        return Err(DecryptError::StartOutOfRange {
+            start: options.start,
+            audio_len,
+        });
+    }
+
+    let encrypted_size = crypto.encrypted_block_size() as u64;
+    let decrypted_size = crypto.decrypted_block_size() as u64;
+    let mut buf = vec![0u8; std::cmp::min(range.end - range.start, encrypted_size) as usize];
+
+    // Read and decrypt the block at `index` into `buf`, returning its plain text.
+    let mut read_block = |index: u64, buf: &mut [u8]| -> Result<usize, DecryptError> {
+        let start = range.start + index * encrypted_size;
+        let len = std::cmp::min(range.end.saturating_sub(start), encrypted_size) as usize;
+        reader.seek(SeekFrom::Start(start))?;
+        reader.read_exact(&mut buf[..len])?;
+        Ok(crypto.decrypt_block(&mut buf[..len])?)
+    };
+
+    let report_progress = |position: u64| {
+        if let Some(on_progress) = options.on_progress {
+            on_progress(Progress {
+                position,
+                total: audio_len,
+            });
+        }
+    };
+
+    let mut format = match audio_len {
+        0 => Some(AudioFormat::Unknown),
+        _ if options.start >= decrypted_size => {
+            let plain_len = read_block(0, &mut buf)?;
+            Some(AudioFormat::sniff(&buf[..plain_len]))
+        }
+        _ => None,
+    };
+
+    let mut index = options.start / decrypted_size;
+    let mut skip = (options.start % decrypted_size) as usize;
+    let mut position = options.start;
+    report_progress(position);
+    while position < audio_len {
+        if options
+            .cancel
+            .is_some_and(|cancel| cancel.load(Ordering::Relaxed))
+        {
+            return Err(DecryptError::Cancelled);
+        }
+
+        let plain_len = read_block(index, &mut buf)?;
+        let plain = &buf[..plain_len];
+        format.get_or_insert_with(|| AudioFormat::sniff(plain));
+        let plain = plain.get(skip..).unwrap_or_default();
+        writer.write_all(plain)?;
+
+        index += 1;
+        skip = 0;
+        position += plain.len() as u64;
+        report_progress(position);
+        if plain.is_empty() {
+            // The blocks hold less than `audio_len` said.
+            return Err(io::Error::from(io::ErrorKind::UnexpectedEof).into());
+        }
+    }
+
+    Ok((
+        format.unwrap_or(AudioFormat::Unknown),
+        position - options.start,
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::crypto::key_dec::generate_ekey;
+    use crate::crypto::test_util::{qtag_file, test_audio, test_key, SONG_ID};
+    use std::cell::RefCell;
+    use std::io::Cursor;
+
+    #[test]
+    fn test_stream_decryptor_from_tail() {
+        let audio = test_audio(b"fLaC", 20000);
+        let file = qtag_file(&audio, &test_key(512));
+        let tail_start = file.len() - RECOMMENDED_TAIL_SIZE;
+
+        let tail = parse_tail(file.len() as u64, &file[tail_start..]).unwrap();
+        assert_eq!(tail.audio_len, 20000);
+        assert_eq!(tail.detection.song_id, SONG_ID);
+
+        let mut decryptor = StreamDecryptor::from_tail(&tail).unwrap();
+        let mut output = vec![];
+        for chunk in file.chunks(777) {
+            let mut chunk = chunk.to_vec();
+            let len = decryptor.decrypt_chunk(&mut chunk);
+            output.extend_from_slice(&chunk[..len]);
+        }
+        assert!(decryptor.is_done());
+        assert_eq!(output, audio);
+    }
+
+    #[test]
+    fn test_parse_tail_too_short() {
+        let file = qtag_file(&test_audio(b"fLaC", 100), &test_key(512));
+        let tail_start = file.len() - RECOMMENDED_DETECTION_SIZE;
+        let err = parse_tail(file.len() as u64, &file[tail_start..]).unwrap_err();
+        assert!(matches!(
+            err,
+            DecryptError::Detection(DetectionError::BufferTooSmall)
+        ));
+    }
+
+    #[test]
+    fn test_decrypt_stream_rc4() {
+        let audio = test_audio(b"fLaC", 30000);
+        let mut input = Cursor::new(qtag_file(&audio, &test_key(512)));
+        let mut output = vec![];
+
+        let report = decrypt_stream(&mut input, &mut output, &Options::default()).unwrap();
+        assert_eq!(output, audio);
+        assert_eq!(report.cipher, CipherKind::RC4);
+        assert_eq!(report.format, AudioFormat::Flac);
+        assert_eq!(report.detection.song_id, SONG_ID);
+        assert_eq!(report.audio_len, 30000);
+        assert_eq!(report.bytes_written, 30000);
+    }
+
+    #[test]
+    fn test_decrypt_stream_map_with_options() {
+        let audio = test_audio(b"fLaC", 1000);
+        let key = test_key(128);
+        let mut input = Cursor::new(qtag_file(&audio, &key));
+        let mut output = vec![];
+        let reports = RefCell::new(vec![]);
+
+        let ekey = generate_ekey(&key);
+        let options = Options {
+            block_size: Some(300),
+            ekey: Some(&ekey),
+            start: 100,
+            on_progress: Some(&|p| reports.borrow_mut().push(p.position)),
+            ..Default::default()
+        };
+        let report = decrypt_stream(&mut input, &mut output, &options).unwrap();
+        assert_eq!(output, &audio[100..]);
+        assert_eq!(report.cipher, CipherKind::Map);
+        assert_eq!(report.bytes_written, 900);
+        assert_eq!(*reports.borrow(), [100, 400, 700, 1000]);
+    }
+
+    #[test]
+    fn test_decrypt_stream_start_out_of_range() {
+        let mut input = Cursor::new(qtag_file(&test_audio(b"fLaC", 100), &test_key(128)));
+        let options = Options {
+            start: 101,
+            ..Default::default()
+        };
+        let err = decrypt_stream(&mut input, &mut vec![], &options).unwrap_err();
+        assert!(matches!(
+            err,
+            DecryptError::StartOutOfRange {
+                start: 101,
+                audio_len: 100
+            }
+        ));
+    }
+
+    #[test]
+    fn test_decrypt_stream_cancelled() {
+        let mut input = Cursor::new(qtag_file(&test_audio(b"fLaC", 100), &test_key(128)));
+        let cancel = AtomicBool::new(true);
+        let options = Options {
+            cancel: Some(&cancel),
+            ..Default::default()
+        };
+        let err = decrypt_stream(&mut input, &mut vec![], &options).unwrap_err();
+        assert!(matches!(err, DecryptError::Cancelled));
+    }
+
+    #[test]
+    fn test_decrypt_stream_unknown_trailer() {
+        let mut input = Cursor::new(vec![0xffu8; 100]);
+        let err = decrypt_stream(&mut input, &mut vec![], &Options::default()).unwrap_err();
+        assert!(matches!(
+            err,
+            DecryptError::Detection(DetectionError::UnknownMagicLE32(_))
+        ));
+        assert_eq!(err.code(), "UNKNOWN_MAGIC");
+    }
+
+    #[test]
+    fn test_decrypt_stream_trailer_before_start() {
+        // A v1 trailer whose ekey would start before the file does.
+        let mut file = test_audio(b"fLaC", 100);
+        file[96..].copy_from_slice(&0x200u32.to_le_bytes());
+        let err =
+            decrypt_stream(&mut Cursor::new(file), &mut vec![], &Options::default()).unwrap_err();
+        assert!(matches!(
+            err,
+            DecryptError::Detection(DetectionError::PositionOutOfRange)
+        ));
+    }
+}
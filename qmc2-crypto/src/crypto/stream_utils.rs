@@ -2,6 +2,7 @@ pub trait StreamExt {
     fn read_u32_be(&self, offset: usize) -> u32;
     fn read_u32_le(&self, offset: usize) -> u32;
     fn write_u32_be(&mut self, offset: usize, value: u32);
+    fn write_u32_le(&mut self, offset: usize, value: u32);
 }
 
 impl StreamExt for [u8] {
@@ -19,6 +20,11 @@ impl StreamExt for [u8] {
     fn write_u32_be(&mut self, offset: usize, value: u32) {
         self[offset..offset + 4].copy_from_slice(&value.to_be_bytes());
     }
+
+    #[inline]
+    fn write_u32_le(&mut self, offset: usize, value: u32) {
+        self[offset..offset + 4].copy_from_slice(&value.to_le_bytes());
+    }
 }
 
 #[cfg(test)]
@@ -43,4 +49,11 @@ mod tests {
         v.write_u32_be(0, 0x01020304);
         assert_eq!(v, [1u8, 2, 3, 4, 0xcc]);
     }
+
+    #[test]
+    fn test_write_u32_le() {
+        let mut v = [0x7fu8, 0xff, 0xee, 0xdd, 0xcc];
+        v.write_u32_le(1, 0x01020304);
+        assert_eq!(v, [0x7fu8, 4, 3, 2, 1]);
+    }
 }
@@ -0,0 +1,69 @@
+//! Helpers shared by the tests of every format, and by the tests of the
+//! other crates through the `test-support` feature.
+
+use std::io::Cursor;
+
+use super::format::{FileReport, Registry};
+use super::key_dec::generate_ekey;
+use super::qmc2::crypto_from_key;
+use super::qmc2_base::QMC2Crypto;
+use super::trailer::build_qtag_trailer;
+use super::xm::{XmCrypto, MAGIC as XM_MAGIC, SEPARATOR as XM_SEPARATOR};
+
+/// What `qtag_file` stores as the song id.
+pub const SONG_ID: &str = "12345";
+
+/// What every encrypted file in `fixtures/` decrypts to, unless said
+/// otherwise: 2 frames of 4096 16-bit mono samples of a sawtooth wave,
+/// stored verbatim.
+pub const SAWTOOTH_FLAC: &[u8] = include_bytes!("../../fixtures/sawtooth.flac");
+
+/// `len` bytes of made-up audio, starting with `magic` so it sniffs as the
+/// format wanted.
+pub fn test_audio(magic: &[u8], len: usize) -> Vec<u8> {
+    let mut audio: Vec<u8> = (0..len).map(|i| (i * 31 % 251) as u8).collect();
+    audio[..magic.len()].copy_from_slice(magic);
+    audio
+}
+
+/// A key of `len` bytes, without zeros.
+pub fn test_key(len: usize) -> Vec<u8> {
+    (0..len).map(|i| (i * 7 + 13) as u8 | 1).collect()
+}
+
+/// `audio` encrypted with `key`, then a QTag trailer.
+pub fn qtag_file(audio: &[u8], key: &[u8]) -> Vec<u8> {
+    let mut data = audio.to_vec();
+    crypto_from_key(key).unwrap().encrypt(0, &mut data);
+    data.extend(build_qtag_trailer(&generate_ekey(key), SONG_ID));
+    data
+}
+
+/// `audio` in an Xiami file with the format tag `tag`, encrypted with `key`
+/// past its first `plain_len` bytes.
+pub fn xm_file(tag: &[u8; 4], plain_len: u32, key: u8, audio: &[u8]) -> Vec<u8> {
+    let mut file = XM_MAGIC.to_vec();
+    file.extend(tag);
+    file.extend(XM_SEPARATOR);
+    file.extend(&plain_len.to_le_bytes()[..3]);
+    file.push(key);
+
+    let mut data = audio.to_vec();
+    XmCrypto::new(plain_len as u64, key).encrypt(0, &mut data);
+    file.extend(data);
+    file
+}
+
+/// Decrypt the whole of `file`, named `*.<ext>`, with the default options.
+pub fn decrypt_all(registry: &Registry, file: &[u8], ext: &str) -> (Vec<u8>, FileReport) {
+    let mut output = vec![];
+    let report = registry
+        .decrypt_stream(
+            &mut Cursor::new(file),
+            &mut output,
+            ext,
+            &Default::default(),
+        )
+        .unwrap();
+    (output, report)
+}
@@ -0,0 +1,140 @@
+use std::io::SeekFrom;
+
+use super::errors::DecryptError;
+use super::format::{Cipher, Confidence, Format, Opened, ReadSeek};
+use super::qmc2_base::QMC2Crypto;
+
+/// What the first 8 bytes of a `.tm2`/`.tm6` file were: the size and type of
+/// the `ftyp` box of an M4A file.
+const M4A_HEADER: [u8; 8] = [0x00, 0x00, 0x00, 0x20, b'f', b't', b'y', b'p'];
+
+/// Puts back the bytes the iOS client replaced; everything else is plain.
+struct TmCrypto {
+    header: Option<[u8; 8]>,
+}
+
+impl QMC2Crypto for TmCrypto {
+    fn get_recommended_block_size(&self) -> usize {
+        0x10000
+    }
+
+    fn decrypt(&self, offset: u64, buf: &mut [u8]) {
+        let Some(header) = &self.header else {
+            return;
+        };
+        if offset < header.len() as u64 {
+            let start = offset as usize;
+            let len = std::cmp::min(header.len() - start, buf.len());
+            buf[..len].copy_from_slice(&header[start..start + len]);
+        }
+    }
+}
+
+/// Audio stored by QQ Music on iOS, which is not encrypted at all.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum TmFormat {
+    /// `.tm2` and `.tm6`: M4A whose first 8 bytes were overwritten.
+    M4a,
+    /// `.tm0` and `.tm3`: MP3 as is.
+    Mp3,
+}
+
+impl Format for TmFormat {
+    fn name(&self) -> &'static str {
+        match self {
+            TmFormat::M4a => "tm-m4a",
+            TmFormat::Mp3 => "tm-mp3",
+        }
+    }
+
+    fn extensions(&self) -> &'static [(&'static str, &'static str)] {
+        match self {
+            TmFormat::M4a => &[("tm2", "m4a"), ("tm6", "m4a")],
+            TmFormat::Mp3 => &[("tm0", "mp3"), ("tm3", "mp3")],
+        }
+    }
+
+    fn probe(&self, _head: &[u8], _tail: &[u8], ext: &str) -> Confidence {
+        // No magic value survives, only the extension tells.
+        match self.extensions().iter().any(|(tm, _)| ext == *tm) {
+            true => Confidence::Likely,
+            false => Confidence::No,
+        }
+    }
+
+    fn open(&self, file: &mut dyn ReadSeek) -> Result<Opened, DecryptError> {
+        let audio_len = file.seek(SeekFrom::End(0))?;
+        let header = match self {
+            TmFormat::M4a => Some(M4A_HEADER),
+            TmFormat::Mp3 => None,
+        };
+        Ok(Opened {
+            cipher: Cipher::Stream(Box::new(TmCrypto { header })),
+            audio_range: 0..audio_len,
+            audio_len,
+            song_id: "".into(),
+            metadata: Default::default(),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::crypto::format::Registry;
+    use crate::crypto::sniff::AudioFormat;
+    use crate::crypto::stream::Options;
+    use crate::crypto::test_util::{decrypt_all, test_audio};
+    use std::io::Cursor;
+
+    fn m4a(len: usize) -> Vec<u8> {
+        test_audio(&[&M4A_HEADER[..], b"M4A "].concat(), len)
+    }
+
+    #[test]
+    fn test_restore_header() {
+        let audio = m4a(5000);
+        let mut file = audio.clone();
+        file[..8].copy_from_slice(b"QQMusic!");
+
+        for start in [0, 5, 8, 100] {
+            let mut output = vec![];
+            let options = Options {
+                start,
+                ..Default::default()
+            };
+            let report = Registry::default()
+                .decrypt_stream(&mut Cursor::new(&file), &mut output, "tm6", &options)
+                .unwrap();
+            assert_eq!(report.container, "tm-m4a");
+            assert_eq!(report.format, AudioFormat::M4a);
+            assert_eq!(output, &audio[start as usize..]);
+        }
+    }
+
+    #[test]
+    fn test_plain_mp3() {
+        let audio = test_audio(b"ID3", 5000);
+        let (output, report) = decrypt_all(&Registry::default(), &audio, "TM3");
+        assert_eq!(report.container, "tm-mp3");
+        assert_eq!(report.format, AudioFormat::Mp3);
+        assert_eq!(output, audio);
+    }
+
+    #[test]
+    fn test_fixture() {
+        // An `ftyp` box, then an `mdat` box holding `sawtooth.flac`.
+        let audio = include_bytes!("../../fixtures/sawtooth.m4a");
+        let file = include_bytes!("../../fixtures/sawtooth.tm6");
+        let (output, report) = decrypt_all(&Registry::default(), file, "tm6");
+        assert_eq!(output, audio);
+        assert_eq!(report.format, AudioFormat::M4a);
+    }
+
+    #[test]
+    fn test_decrypted_extension() {
+        let registry = Registry::default();
+        assert_eq!(registry.decrypted_extension("tm2"), Some("m4a"));
+        assert_eq!(registry.decrypted_extension("tm0"), Some("mp3"));
+    }
+}
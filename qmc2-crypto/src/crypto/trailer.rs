@@ -0,0 +1,60 @@
+use super::stream_utils::StreamExt;
+
+/// The two kinds of trailer a QMC2 file can end with.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum TrailerKind {
+    /// Just the ekey and its length; there is no room for a song id.
+    V1,
+    #[default]
+    QTag,
+}
+
+/// Build the v1 trailer: the ekey, then its length as little-endian u32.
+///
+/// Only recognised if the ekey is at most `MAX_V1_EKEY_LEN` bytes long.
+pub fn build_v1_trailer(ekey: &str) -> Vec<u8> {
+    let mut trailer = ekey.as_bytes().to_vec();
+    let ekey_len = trailer.len();
+    trailer.resize(ekey_len + 4, 0);
+    trailer.write_u32_le(ekey_len, ekey_len as u32);
+    trailer
+}
+
+/// Build the QTag (v2) trailer that goes after the encrypted audio:
+/// `ekey,song_id,2`, its length as big-endian u32, then `QTag`.
+pub fn build_qtag_trailer(ekey: &str, song_id: &str) -> Vec<u8> {
+    let meta = format!("{},{},2", ekey, song_id);
+
+    let mut trailer = meta.into_bytes();
+    let meta_len = trailer.len();
+    trailer.resize(meta_len + 8, 0);
+    trailer.write_u32_be(meta_len, meta_len as u32);
+    trailer[meta_len + 4..].copy_from_slice(b"QTag");
+    trailer
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::crypto::detection::{detect, Detection};
+
+    #[test]
+    fn test_build_v1_trailer() {
+        let trailer = build_v1_trailer("aaaa");
+        assert_eq!(trailer, b"aaaa\x04\0\0\0");
+        assert_eq!(
+            detect(&trailer).unwrap(),
+            Detection::new(0, 0, 4, "".into())
+        );
+    }
+
+    #[test]
+    fn test_build_qtag_trailer() {
+        let trailer = build_qtag_trailer("aaaa", "18");
+        assert_eq!(trailer, b"aaaa,18,2\0\0\0\x09QTag");
+        assert_eq!(
+            detect(&trailer).unwrap(),
+            Detection::new(0, 0, 4, "18".into())
+        );
+    }
+}
@@ -0,0 +1,174 @@
+use std::io::SeekFrom;
+
+use super::errors::{DecryptError, DetectionError};
+use super::format::{Cipher, Confidence, Format, Metadata, Opened, ReadSeek};
+use super::qmc2_base::QMC2Crypto;
+
+/// `ifmt`, a format tag, then this separator.
+pub(super) const MAGIC: &[u8; 4] = b"ifmt";
+pub(super) const SEPARATOR: &[u8; 4] = b"\xfe\xfe\xfe\xfe";
+const FORMAT_TAG_POSITION: usize = 4;
+/// 3 bytes, little-endian: how much of the audio is stored as is.
+const PLAIN_LEN_POSITION: usize = 0x0c;
+const KEY_POSITION: usize = 0x0f;
+const HEADER_SIZE: usize = 0x10;
+
+/// Everything past a plain prefix has the key subtracted and is inverted.
+pub struct XmCrypto {
+    plain_len: u64,
+    key: u8,
+}
+
+impl XmCrypto {
+    pub fn new(plain_len: u64, key: u8) -> Self {
+        XmCrypto { plain_len, key }
+    }
+
+    /// The part of `buf`, found at `offset` in the audio data, that is encrypted.
+    fn encrypted<'a>(&self, offset: u64, buf: &'a mut [u8]) -> &'a mut [u8] {
+        let skip = std::cmp::min(self.plain_len.saturating_sub(offset), buf.len() as u64);
+        &mut buf[skip as usize..]
+    }
+}
+
+impl QMC2Crypto for XmCrypto {
+    fn get_recommended_block_size(&self) -> usize {
+        0x10000
+    }
+
+    fn decrypt(&self, offset: u64, buf: &mut [u8]) {
+        for value in self.encrypted(offset, buf) {
+            *value = value.wrapping_sub(self.key) ^ 0xff;
+        }
+    }
+
+    fn encrypt(&self, offset: u64, buf: &mut [u8]) {
+        for value in self.encrypted(offset, buf) {
+            *value = (*value ^ 0xff).wrapping_add(self.key);
+        }
+    }
+}
+
+/// The audio format named by a tag such as `" MP3"`; `" A4M"` is M4A.
+fn parse_format_tag(tag: &[u8]) -> Option<String> {
+    match tag {
+        b" MP3" => Some("mp3".into()),
+        b" A4M" => Some("m4a".into()),
+        b"FLAC" => Some("flac".into()),
+        b" WAV" => Some("wav".into()),
+        _ => None,
+    }
+}
+
+/// Xiami files (`.xm`): a 16-byte header, then partly encrypted audio data.
+pub struct XmFormat;
+
+impl Format for XmFormat {
+    fn name(&self) -> &'static str {
+        "xm"
+    }
+
+    fn extensions(&self) -> &'static [(&'static str, &'static str)] {
+        // The format tag in the header says which.
+        &[("xm", "")]
+    }
+
+    fn probe(&self, head: &[u8], _tail: &[u8], _ext: &str) -> Confidence {
+        let separator = head.get(8..12);
+        match head.starts_with(MAGIC) && separator == Some(SEPARATOR) {
+            true => Confidence::Certain,
+            false => Confidence::No,
+        }
+    }
+
+    fn open(&self, file: &mut dyn ReadSeek) -> Result<Opened, DecryptError> {
+        let file_len = file.seek(SeekFrom::End(0))?;
+        if file_len < HEADER_SIZE as u64 {
+            return Err(DetectionError::BufferTooSmall.into());
+        }
+
+        let mut header = [0u8; HEADER_SIZE];
+        file.seek(SeekFrom::Start(0))?;
+        file.read_exact(&mut header)?;
+        if !header.starts_with(MAGIC) || header[8..12] != *SEPARATOR {
+            return Err(DecryptError::UnknownFormat);
+        }
+
+        let mut plain_len = [0u8; 4];
+        plain_len[..3].copy_from_slice(&header[PLAIN_LEN_POSITION..PLAIN_LEN_POSITION + 3]);
+        let plain_len = u32::from_le_bytes(plain_len) as u64;
+        let tag = &header[FORMAT_TAG_POSITION..FORMAT_TAG_POSITION + 4];
+
+        Ok(Opened {
+            cipher: Cipher::Stream(Box::new(XmCrypto::new(plain_len, header[KEY_POSITION]))),
+            audio_range: HEADER_SIZE as u64..file_len,
+            audio_len: file_len - HEADER_SIZE as u64,
+            song_id: "".into(),
+            metadata: Metadata {
+                format: parse_format_tag(tag),
+                ..Default::default()
+            },
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::crypto::format::Registry;
+    use crate::crypto::reader::QMC2Reader;
+    use crate::crypto::sniff::AudioFormat;
+    use crate::crypto::test_util::{decrypt_all, test_audio, xm_file, SAWTOOTH_FLAC};
+    use std::io::{Cursor, Read, Seek};
+
+    fn wav() -> Vec<u8> {
+        test_audio(b"RIFF\0\0\0\0WAVE", 50000)
+    }
+
+    #[test]
+    fn test_decrypt_xm() {
+        let audio = wav();
+        let file = xm_file(b" WAV", 1000, 0x5a, &audio);
+        assert_eq!(file[HEADER_SIZE..HEADER_SIZE + 1000], audio[..1000]);
+
+        let (output, report) = decrypt_all(&Registry::default(), &file, "xm");
+        assert_eq!(output, audio);
+        assert_eq!(report.container, "xm");
+        assert_eq!(report.format, AudioFormat::Wav);
+        assert_eq!(report.metadata.format.as_deref(), Some("wav"));
+    }
+
+    #[test]
+    fn test_seek_across_plain_prefix() {
+        let audio = wav();
+        let file = xm_file(b" A4M", 1000, 0x33, &audio);
+
+        let file_len = file.len() as u64;
+        let mut reader = QMC2Reader::open(Cursor::new(file), &Registry::default(), "xm").unwrap();
+        assert_eq!(reader.audio_range(), HEADER_SIZE as u64..file_len);
+        reader.seek(SeekFrom::Start(990)).unwrap();
+        let mut buf = [0u8; 20];
+        reader.read_exact(&mut buf).unwrap();
+        assert_eq!(buf, &audio[990..1010]);
+
+        // Past the header, this would overflow.
+        let err = reader.seek(SeekFrom::Start(u64::MAX)).unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::InvalidInput);
+    }
+
+    #[test]
+    fn test_fixture() {
+        // `xm_file(b"FLAC", 1000, 0x5a, SAWTOOTH_FLAC)`
+        let file = include_bytes!("../../fixtures/sawtooth.xm");
+        let (output, report) = decrypt_all(&Registry::default(), file, "xm");
+        assert_eq!(output, SAWTOOTH_FLAC);
+        assert_eq!(report.metadata.format.as_deref(), Some("flac"));
+    }
+
+    #[test]
+    fn test_parse_format_tag() {
+        assert_eq!(parse_format_tag(b" A4M").as_deref(), Some("m4a"));
+        assert_eq!(parse_format_tag(b"FLAC").as_deref(), Some("flac"));
+        assert_eq!(parse_format_tag(b"OGG "), None);
+    }
+}
@@ -1,10 +1,29 @@
 mod crypto;
 
+#[cfg(feature = "tokio")]
+pub use crypto::async_reader::AsyncQMC2Reader;
 pub use crypto::detection;
 pub use crypto::errors;
+pub use crypto::format;
+pub use crypto::format::{Format, Metadata, Registry};
+#[cfg(feature = "joox")]
+pub use crypto::joox;
 pub use crypto::key_dec::*;
-pub use crypto::qmc2::decrypt_factory;
-pub use crypto::qmc2_base::QMC2Crypto;
+pub use crypto::kwm::KwmFormat;
+#[cfg(feature = "ncm")]
+pub use crypto::ncm::NcmFormat;
+pub use crypto::qmc2::{crypto_from_key, decrypt_factory, CipherKind, QMC2Format};
+pub use crypto::qmc2_base::{BlockCrypto, QMC2Crypto};
+pub use crypto::reader::QMC2Reader;
+pub use crypto::rewrap::{rewrap_stream, RewrapOptions, RewrapReport};
+pub use crypto::sniff;
+pub use crypto::stream;
+pub use crypto::stream::{decrypt_stream, StreamDecryptor};
+#[cfg(feature = "test-support")]
+pub use crypto::test_util;
+pub use crypto::tm::TmFormat;
+pub use crypto::trailer;
+pub use crypto::xm::XmFormat;
 
 #[cfg(test)]
 mod tests {
@@ -0,0 +1,77 @@
+import { Transform, TransformOptions } from "stream";
+
+export interface Detection {
+  /** Relative to the start of the buffer given to `detect`; can be negative. */
+  eofPosition: number;
+  ekeyPosition: number;
+  ekeyLen: number;
+  songId: string;
+}
+
+export interface DecryptReport {
+  songId: string;
+  cipher: "map" | "rc4";
+  /** Sniffed from the decrypted audio, without the leading dot (`"bin"` if unknown). */
+  extension: string;
+  mimeType: string;
+  audioSize: number;
+}
+
+/**
+ * Errors thrown (or rejected with) by this module carry a stable `code`,
+ * e.g. `BUFFER_TOO_SMALL`, `UNKNOWN_MAGIC`, `EKEY_PARSE`, `IO`.
+ */
+export interface QMC2Error extends Error {
+  code: string;
+}
+
+export function getRecommendedDetectionSize(): number;
+export function getRecommendedTailSize(): number;
+export function detect(buf: Buffer): Detection;
+export function decryptFactory(ekey: string): QMC2Crypto;
+
+/** Decrypt the file at `input` into `output` on the libuv threadpool. */
+export function decryptFile(input: string, output: string): Promise<DecryptReport>;
+
+export class QMC2Crypto {
+  getRecommendedBlockSize(): number;
+  /** Decrypt `buf` in place, as if it started at `offset` in the audio data. */
+  decrypt(offset: number, buf: Buffer): void;
+}
+
+export class StreamDecryptor {
+  /** Decrypt with a known ekey; `audioSize` is the file size without its trailer. */
+  constructor(ekey: string, audioSize: number);
+  /**
+   * Set up from the size of the file and its last `getRecommendedTailSize()`
+   * bytes (or all of it, if smaller).
+   */
+  static fromTail(fileSize: number, tail: Buffer): StreamDecryptor;
+  /**
+   * Decrypt the next chunk of the file in place. Returns how many bytes at
+   * the start of `chunk` are audio; the rest belongs to the trailer.
+   */
+  decryptChunk(chunk: Buffer): number;
+  readonly songId: string;
+  readonly audioSize: number;
+  /** Bytes fed so far. */
+  readonly position: number;
+  readonly isDone: boolean;
+}
+
+/**
+ * A `Transform` stream that decrypts a QMC2 file fed through it from the
+ * start, and drops its trailer. Chunks are decrypted in place.
+ */
+export class DecryptStream extends Transform {
+  constructor(decryptor: StreamDecryptor, options?: TransformOptions);
+  readonly decryptor: StreamDecryptor;
+}
+
+/**
+ * Open `path` and return a readable stream of its decrypted audio, along with
+ * what the trailer said about it.
+ */
+export function createDecryptStream(
+  path: string
+): Promise<{ stream: DecryptStream; songId: string; audioSize: number }>;
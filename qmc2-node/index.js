@@ -0,0 +1,72 @@
+"use strict";
+
+const fs = require("fs");
+const { Transform } = require("stream");
+const native = require("./qmc2.node");
+
+/**
+ * A `Transform` stream that decrypts a QMC2 file fed through it from the
+ * start, and drops its trailer.
+ *
+ * Chunks are decrypted in place, so don't reuse them after writing them.
+ */
+class DecryptStream extends Transform {
+  /**
+   * @param {native.StreamDecryptor} decryptor
+   * @param {import("stream").TransformOptions} [options]
+   */
+  constructor(decryptor, options) {
+    super(options);
+    this.decryptor = decryptor;
+  }
+
+  _transform(chunk, encoding, callback) {
+    try {
+      const len = this.decryptor.decryptChunk(chunk);
+      if (len > 0) {
+        this.push(len === chunk.length ? chunk : chunk.subarray(0, len));
+      }
+      callback();
+    } catch (err) {
+      callback(err);
+    }
+  }
+}
+
+/**
+ * Open `path` and return a readable stream of its decrypted audio, along with
+ * what the trailer said about it.
+ *
+ * @param {string} path
+ * @returns {Promise<{ stream: DecryptStream, songId: string, audioSize: number }>}
+ */
+async function createDecryptStream(path) {
+  const handle = await fs.promises.open(path, "r");
+  let decryptor;
+  try {
+    const { size } = await handle.stat();
+    const tailSize = Math.min(size, native.getRecommendedTailSize());
+    const tail = Buffer.alloc(tailSize);
+    await handle.read(tail, 0, tailSize, size - tailSize);
+    decryptor = native.StreamDecryptor.fromTail(size, tail);
+  } finally {
+    await handle.close();
+  }
+
+  const stream = new DecryptStream(decryptor);
+  const input = fs.createReadStream(path);
+  input.on("error", (err) => stream.destroy(err));
+  input.pipe(stream);
+
+  return {
+    stream,
+    songId: decryptor.songId,
+    audioSize: decryptor.audioSize,
+  };
+}
+
+module.exports = {
+  ...native,
+  DecryptStream,
+  createDecryptStream,
+};
@@ -0,0 +1,28 @@
+use napi::{Env, Error, JsError};
+use qmc2_crypto::errors::DecryptError;
+
+/// Turn an error from the core into a JS `Error` whose `code` is the stable
+/// code from the core (e.g. `UNKNOWN_MAGIC`), so callers need not match on
+/// messages.
+pub(crate) fn js_error<E: Into<DecryptError>>(env: Env, err: E) -> Error {
+    let err = err.into();
+    new_error(env, err.code(), &err.to_string())
+}
+
+/// JS numbers are signed; offsets and sizes must not be.
+pub(crate) fn to_offset(env: Env, value: i64) -> Result<u64, Error> {
+    u64::try_from(value).map_err(|_| {
+        new_error(
+            env,
+            "OFFSET_OUT_OF_RANGE",
+            &format!("{} is not a valid offset", value),
+        )
+    })
+}
+
+fn new_error(env: Env, code: &str, message: &str) -> Error {
+    // Going through a JS value keeps the custom `code`; a plain `Error`
+    // would only carry a napi `Status`.
+    let js_error = JsError::from(Error::new(code, message)).into_unknown(env);
+    Error::from(js_error)
+}
@@ -0,0 +1,195 @@
+#[macro_use]
+extern crate napi_derive;
+
+mod errors;
+
+use errors::{js_error, to_offset};
+use napi::bindgen_prelude::*;
+use qmc2_crypto as qmc2;
+use qmc2_crypto::errors::DecryptError;
+use qmc2_crypto::stream::Report;
+use std::fs::{self, File};
+use std::io::{BufWriter, Write};
+
+#[napi(object)]
+pub struct Detection {
+    /// Relative to the start of the buffer given to `detect`; can be negative.
+    pub eof_position: i64,
+    pub ekey_position: i64,
+    pub ekey_len: u32,
+    pub song_id: String,
+}
+
+#[napi]
+pub fn get_recommended_detection_size() -> u32 {
+    qmc2::detection::RECOMMENDED_DETECTION_SIZE as u32
+}
+
+#[napi]
+pub fn get_recommended_tail_size() -> u32 {
+    qmc2::stream::RECOMMENDED_TAIL_SIZE as u32
+}
+
+#[napi]
+pub fn detect(env: Env, buf: Buffer) -> Result<Detection> {
+    let detection = qmc2::detection::detect(&buf).map_err(|e| js_error(env, e))?;
+    Ok(Detection {
+        eof_position: detection.eof_position,
+        ekey_position: detection.ekey_position,
+        ekey_len: detection.ekey_len as u32,
+        song_id: detection.song_id,
+    })
+}
+
+#[napi]
+pub struct QMC2Crypto(Box<dyn qmc2::QMC2Crypto>);
+
+#[napi]
+impl QMC2Crypto {
+    #[napi]
+    pub fn get_recommended_block_size(&self) -> u32 {
+        self.0.get_recommended_block_size() as u32
+    }
+
+    /// Decrypt `buf` in place, as if it started at `offset` in the audio data.
+    #[napi]
+    pub fn decrypt(&self, env: Env, offset: i64, mut buf: Buffer) -> Result<()> {
+        self.0.decrypt(to_offset(env, offset)?, &mut buf);
+        Ok(())
+    }
+}
+
+#[napi]
+pub fn decrypt_factory(env: Env, ekey: String) -> Result<QMC2Crypto> {
+    qmc2::decrypt_factory(&ekey)
+        .map(QMC2Crypto)
+        .map_err(|e| js_error(env, e))
+}
+
+/// Decrypts a file fed in order, chunk by chunk; `DecryptStream` wraps it
+/// as a `Transform` stream.
+#[napi]
+pub struct StreamDecryptor {
+    inner: qmc2::StreamDecryptor,
+    song_id: String,
+}
+
+#[napi]
+impl StreamDecryptor {
+    /// Decrypt with a known ekey; `audio_size` is the file size without its trailer.
+    #[napi(constructor)]
+    pub fn new(env: Env, ekey: String, audio_size: i64) -> Result<Self> {
+        let audio_size = to_offset(env, audio_size)?;
+        let crypto = qmc2::decrypt_factory(&ekey).map_err(|e| js_error(env, e))?;
+        Ok(StreamDecryptor {
+            inner: qmc2::StreamDecryptor::new(crypto, audio_size),
+            song_id: "".into(),
+        })
+    }
+
+    /// Set up from the size of the file and its last
+    /// `getRecommendedTailSize()` bytes (or all of it, if smaller).
+    #[napi(factory)]
+    pub fn from_tail(env: Env, file_size: i64, tail: Buffer) -> Result<Self> {
+        let tail = qmc2::stream::parse_tail(to_offset(env, file_size)?, &tail)
+            .map_err(|e| js_error(env, e))?;
+        let inner = qmc2::StreamDecryptor::from_tail(&tail).map_err(|e| js_error(env, e))?;
+        Ok(StreamDecryptor {
+            inner,
+            song_id: tail.detection.song_id,
+        })
+    }
+
+    /// Decrypt the next chunk of the file in place.
+    ///
+    /// Returns how many bytes at the start of `chunk` are audio; the rest
+    /// belongs to the trailer and should be dropped.
+    #[napi]
+    pub fn decrypt_chunk(&mut self, mut chunk: Buffer) -> u32 {
+        self.inner.decrypt_chunk(&mut chunk) as u32
+    }
+
+    #[napi(getter)]
+    pub fn song_id(&self) -> String {
+        self.song_id.clone()
+    }
+
+    #[napi(getter)]
+    pub fn audio_size(&self) -> i64 {
+        self.inner.audio_len() as i64
+    }
+
+    /// Bytes fed so far.
+    #[napi(getter)]
+    pub fn position(&self) -> i64 {
+        self.inner.position() as i64
+    }
+
+    #[napi(getter)]
+    pub fn is_done(&self) -> bool {
+        self.inner.is_done()
+    }
+}
+
+#[napi(object)]
+pub struct DecryptReport {
+    pub song_id: String,
+    /// `"map"` or `"rc4"`.
+    pub cipher: String,
+    /// Sniffed from the decrypted audio, without the leading dot (`"bin"` if unknown).
+    pub extension: String,
+    pub mime_type: String,
+    pub audio_size: i64,
+}
+
+pub struct DecryptFileTask {
+    input: String,
+    output: String,
+}
+
+impl DecryptFileTask {
+    fn run(&self) -> std::result::Result<Report, DecryptError> {
+        let mut reader = File::open(&self.input)?;
+        let mut writer = BufWriter::new(File::create(&self.output)?);
+        let report = qmc2::decrypt_stream(&mut reader, &mut writer, &Default::default())?;
+        writer.flush()?;
+        Ok(report)
+    }
+}
+
+impl Task for DecryptFileTask {
+    type Output = std::result::Result<Report, DecryptError>;
+    type JsValue = DecryptReport;
+
+    fn compute(&mut self) -> Result<Self::Output> {
+        let result = self.run();
+        if result.is_err() {
+            // Don't leave a half-written (or empty) output behind.
+            let _ = fs::remove_file(&self.output);
+        }
+        Ok(result)
+    }
+
+    fn resolve(&mut self, env: Env, output: Self::Output) -> Result<Self::JsValue> {
+        // The error is turned into a JS error here rather than in `compute`,
+        // as that needs the `Env` to attach its `code`.
+        let report = output.map_err(|e| js_error(env, e))?;
+        Ok(DecryptReport {
+            song_id: report.detection.song_id,
+            cipher: match report.cipher {
+                qmc2::CipherKind::Map => "map",
+                qmc2::CipherKind::RC4 => "rc4",
+            }
+            .into(),
+            extension: report.format.extension().into(),
+            mime_type: report.format.mime_type().into(),
+            audio_size: report.audio_len as i64,
+        })
+    }
+}
+
+/// Decrypt the file at `input` into `output` on the libuv threadpool.
+#[napi]
+pub fn decrypt_file(input: String, output: String) -> AsyncTask<DecryptFileTask> {
+    AsyncTask::new(DecryptFileTask { input, output })
+}
@@ -0,0 +1,73 @@
+"use strict";
+
+// Run with `npm test` after `npm run build`.
+
+const assert = require("assert");
+const { execFileSync } = require("child_process");
+const crypto = require("crypto");
+const fs = require("fs");
+const os = require("os");
+const path = require("path");
+const { after, test } = require("node:test");
+const { pipeline } = require("stream/promises");
+const qmc2 = require("..");
+
+const dir = fs.mkdtempSync(path.join(os.tmpdir(), "qmc2-node-"));
+const plainPath = path.join(dir, "plain.flac");
+const plain = Buffer.concat([Buffer.from("fLaC"), crypto.randomBytes(3000000)]);
+fs.writeFileSync(plainPath, plain);
+after(() => fs.rmSync(dir, { recursive: true, force: true }));
+
+function makeFixture(keyLen) {
+  const fixturePath = path.join(dir, `test-${keyLen}.mflac`);
+  execFileSync("cargo", [
+    "run", "-q", "-p", "qmc2-crypto", "--features", "qmc2-crypto/test-support",
+    "--example", "make_fixture", "--",
+    plainPath, fixturePath, String(keyLen),
+  ]);
+  return fixturePath;
+}
+
+const fixtures = [makeFixture(512), makeFixture(256)];
+
+test("decryptFile", async () => {
+  for (const fixture of fixtures) {
+    const output = fixture + ".flac";
+    const report = await qmc2.decryptFile(fixture, output);
+    assert.strictEqual(report.songId, "12345");
+    assert.strictEqual(report.extension, "flac");
+    assert.strictEqual(report.audioSize, plain.length);
+    assert.ok(fs.readFileSync(output).equals(plain));
+  }
+});
+
+test("decryptFile rejects with a code", async () => {
+  await assert.rejects(qmc2.decryptFile(plainPath, path.join(dir, "out")), {
+    code: "UNKNOWN_MAGIC",
+  });
+});
+
+test("createDecryptStream", async () => {
+  const output = path.join(dir, "stream.flac");
+  const { stream, songId, audioSize } = await qmc2.createDecryptStream(fixtures[0]);
+  assert.strictEqual(songId, "12345");
+  assert.strictEqual(audioSize, plain.length);
+  await pipeline(stream, fs.createWriteStream(output));
+  assert.ok(fs.readFileSync(output).equals(plain));
+});
+
+test("detect and decryptFactory", () => {
+  const file = fs.readFileSync(fixtures[1]);
+  const tail = file.subarray(file.length - qmc2.getRecommendedDetectionSize());
+  const detection = qmc2.detect(tail);
+  assert.strictEqual(detection.songId, "12345");
+
+  const ekeyStart = file.length - tail.length + detection.ekeyPosition;
+  const ekey = file.subarray(ekeyStart, ekeyStart + detection.ekeyLen).toString();
+  const audio = file.subarray(0, file.length - tail.length + detection.eofPosition);
+  qmc2.decryptFactory(ekey).decrypt(0, audio);
+  assert.ok(audio.equals(plain));
+
+  assert.throws(() => qmc2.detect(Buffer.alloc(4)), { code: "BUFFER_TOO_SMALL" });
+  assert.throws(() => qmc2.decryptFactory("!!"), { code: "EKEY_PARSE" });
+});
@@ -0,0 +1,30 @@
+use pyo3::create_exception;
+use pyo3::exceptions::PyException;
+use pyo3::prelude::*;
+use qmc2_crypto::errors::DecryptError;
+
+create_exception!(
+    qmc2,
+    QMC2Error,
+    PyException,
+    "Raised for files or keys that cannot be handled; `code` tells which problem \
+     it was, e.g. `UNKNOWN_MAGIC` or `EKEY_PARSE`."
+);
+
+/// Turn an error from the core into a `QMC2Error` with its stable `code`.
+///
+/// I/O errors become the matching `OSError` instead, or the original
+/// exception when they came from a Python file object.
+pub(crate) fn py_error<E: Into<DecryptError>>(err: E) -> PyErr {
+    let err = err.into();
+    if let DecryptError::Io(err) = err {
+        return err.into();
+    }
+
+    let py_err = QMC2Error::new_err(err.to_string());
+    Python::with_gil(|py| {
+        // Setting an attribute on a fresh exception cannot fail.
+        let _ = py_err.value(py).setattr("code", err.code());
+    });
+    py_err
+}
@@ -0,0 +1,193 @@
+use crate::errors::py_error;
+use pyo3::buffer::PyBuffer;
+use pyo3::exceptions::{PyTypeError, PyValueError};
+use pyo3::prelude::*;
+use pyo3::types::PyBytes;
+use qmc2_crypto::QMC2Reader;
+use std::io::{self, Read, Seek, SeekFrom};
+
+/// A Python file object, seen from Rust.
+struct PyFile(PyObject);
+
+impl Read for PyFile {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        Python::with_gil(|py| {
+            let data = self.0.call_method1(py, "read", (buf.len(),))?;
+            let data = data.downcast_bound::<PyBytes>(py).map_err(PyErr::from)?;
+            let data = data.as_bytes();
+            // A misbehaving file object could return more than asked for.
+            let n = std::cmp::min(data.len(), buf.len());
+            buf[..n].copy_from_slice(&data[..n]);
+            Ok(n)
+        })
+    }
+}
+
+impl Seek for PyFile {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        let (offset, whence) = match pos {
+            SeekFrom::Start(offset) => (offset as i64, 0),
+            SeekFrom::Current(offset) => (offset, 1),
+            SeekFrom::End(offset) => (offset, 2),
+        };
+        Python::with_gil(|py| {
+            let position = self.0.call_method1(py, "seek", (offset, whence))?;
+            Ok(position.extract::<u64>(py)?)
+        })
+    }
+}
+
+/// A read-only, seekable file over the decrypted audio of a QMC2 file.
+///
+/// Wraps any binary file object that has `read` and `seek`; positions are
+/// within the audio, the trailer is not visible. Closing it does not close
+/// the wrapped file.
+///
+/// ```python
+/// with open("song.mflac", "rb") as f:
+///     audio = qmc2.QMC2File(f)
+///     tags = mutagen.File(audio)
+/// ```
+#[pyclass(module = "qmc2")]
+pub struct QMC2File {
+    reader: Option<QMC2Reader<PyFile>>,
+}
+
+impl QMC2File {
+    fn reader(&mut self) -> PyResult<&mut QMC2Reader<PyFile>> {
+        self.reader
+            .as_mut()
+            .ok_or_else(|| PyValueError::new_err("I/O operation on closed file."))
+    }
+}
+
+#[pymethods]
+impl QMC2File {
+    /// Read the trailer of `fileobj`; `ekey` overrides the embedded one.
+    #[new]
+    #[pyo3(signature = (fileobj, ekey=None))]
+    fn new(fileobj: PyObject, ekey: Option<&str>) -> PyResult<Self> {
+        let reader = QMC2Reader::with_ekey(PyFile(fileobj), ekey).map_err(py_error)?;
+        Ok(QMC2File {
+            reader: Some(reader),
+        })
+    }
+
+    /// Read up to `size` bytes, or everything left if `size` is negative.
+    #[pyo3(signature = (size=-1))]
+    fn read<'py>(&mut self, py: Python<'py>, size: i64) -> PyResult<Bound<'py, PyBytes>> {
+        let reader = self.reader()?;
+        let mut data = vec![];
+        if size < 0 {
+            reader.read_to_end(&mut data)?;
+        } else {
+            reader.by_ref().take(size as u64).read_to_end(&mut data)?;
+        }
+        Ok(PyBytes::new(py, &data))
+    }
+
+    /// Read into any writable, contiguous buffer (a `bytearray`, a
+    /// `memoryview`, ...), returning the number of bytes read.
+    fn readinto(&mut self, py: Python<'_>, buf: PyBuffer<u8>) -> PyResult<usize> {
+        if buf.readonly() || !buf.is_c_contiguous() {
+            return Err(PyTypeError::new_err(
+                "readinto() needs a writable, contiguous buffer",
+            ));
+        }
+
+        let reader = self.reader()?;
+        // Reading calls into the wrapped file object, so read into our own
+        // buffer first rather than holding on to `buf`'s memory meanwhile.
+        let mut data = vec![0u8; buf.item_count()];
+        let n = read_fully(reader, &mut data)?;
+        let cells = buf
+            .as_mut_slice(py)
+            .ok_or_else(|| PyTypeError::new_err("readinto() needs a writable buffer"))?;
+        for (cell, &value) in cells.iter().zip(&data[..n]) {
+            cell.set(value);
+        }
+        Ok(n)
+    }
+
+    #[pyo3(signature = (offset, whence=0))]
+    fn seek(&mut self, offset: i64, whence: i32) -> PyResult<u64> {
+        let pos = match whence {
+            0 => SeekFrom::Start(u64::try_from(offset).map_err(|_| {
+                PyValueError::new_err(format!("negative seek position {}", offset))
+            })?),
+            1 => SeekFrom::Current(offset),
+            2 => SeekFrom::End(offset),
+            _ => {
+                return Err(PyValueError::new_err(format!(
+                    "invalid whence ({})",
+                    whence
+                )))
+            }
+        };
+        Ok(self.reader()?.seek(pos)?)
+    }
+
+    fn tell(&mut self) -> PyResult<u64> {
+        Ok(self.reader()?.stream_position()?)
+    }
+
+    fn readable(&self) -> bool {
+        true
+    }
+
+    fn seekable(&self) -> bool {
+        true
+    }
+
+    fn writable(&self) -> bool {
+        false
+    }
+
+    /// Nothing to flush, as the file is read-only.
+    fn flush(&mut self) -> PyResult<()> {
+        self.reader()?;
+        Ok(())
+    }
+
+    fn close(&mut self) {
+        self.reader = None;
+    }
+
+    #[getter]
+    fn closed(&self) -> bool {
+        self.reader.is_none()
+    }
+
+    #[getter]
+    fn song_id(&mut self) -> PyResult<String> {
+        Ok(self.reader()?.song_id().into())
+    }
+
+    /// Size of the audio data, i.e. the file without its trailer.
+    #[getter]
+    fn audio_size(&mut self) -> PyResult<u64> {
+        Ok(self.reader()?.audio_len())
+    }
+
+    fn __enter__(slf: Py<Self>) -> Py<Self> {
+        slf
+    }
+
+    fn __exit__(&mut self, _exc_type: PyObject, _exc_value: PyObject, _traceback: PyObject) {
+        self.close();
+    }
+}
+
+/// Read until `buf` is full or the end is reached.
+fn read_fully<R: Read>(reader: &mut R, buf: &mut [u8]) -> io::Result<usize> {
+    let mut total = 0;
+    while total < buf.len() {
+        match reader.read(&mut buf[total..]) {
+            Ok(0) => break,
+            Ok(n) => total += n,
+            Err(err) if err.kind() == io::ErrorKind::Interrupted => {}
+            Err(err) => return Err(err),
+        }
+    }
+    Ok(total)
+}
@@ -0,0 +1,96 @@
+mod errors;
+mod file;
+
+use errors::{py_error, QMC2Error};
+use file::QMC2File;
+use pyo3::prelude::*;
+use pyo3::types::{PyByteArray, PyBytes};
+use qmc2_crypto as crypto;
+
+/// What the trailer at the end of a file says about it.
+#[pyclass(module = "qmc2", frozen, get_all)]
+pub struct Detection {
+    /// Relative to the start of the buffer given to `detect`; can be negative.
+    eof_position: i64,
+    ekey_position: i64,
+    ekey_len: usize,
+    song_id: String,
+}
+
+#[pymethods]
+impl Detection {
+    fn __repr__(&self) -> String {
+        format!(
+            "Detection(eof_position={}, ekey_position={}, ekey_len={}, song_id={:?})",
+            self.eof_position, self.ekey_position, self.ekey_len, self.song_id
+        )
+    }
+}
+
+/// Find the trailer in the last bytes of a file, at least
+/// `RECOMMENDED_DETECTION_SIZE` of them.
+#[pyfunction]
+fn detect(buf: &[u8]) -> PyResult<Detection> {
+    let detection = crypto::detection::detect(buf).map_err(py_error)?;
+    Ok(Detection {
+        eof_position: detection.eof_position,
+        ekey_position: detection.ekey_position,
+        ekey_len: detection.ekey_len,
+        song_id: detection.song_id,
+    })
+}
+
+/// Decode an ekey into the key it protects.
+#[pyfunction]
+fn parse_ekey<'py>(py: Python<'py>, ekey: &str) -> PyResult<Bound<'py, PyBytes>> {
+    let key = crypto::parse_ekey(ekey).map_err(py_error)?;
+    Ok(PyBytes::new(py, &key))
+}
+
+/// Decrypts blocks of audio data at any offset.
+#[pyclass(module = "qmc2", frozen)]
+pub struct Decryptor(Box<dyn crypto::QMC2Crypto>);
+
+#[pymethods]
+impl Decryptor {
+    #[new]
+    fn new(ekey: &str) -> PyResult<Self> {
+        crypto::decrypt_factory(ekey)
+            .map(Decryptor)
+            .map_err(py_error)
+    }
+
+    /// From a key already decoded, e.g. by `parse_ekey`; at least 8 bytes.
+    #[staticmethod]
+    fn from_key(key: &[u8]) -> PyResult<Self> {
+        crypto::crypto_from_key(key)
+            .map(Decryptor)
+            .map_err(py_error)
+    }
+
+    #[getter]
+    fn block_size(&self) -> usize {
+        self.0.get_recommended_block_size()
+    }
+
+    /// Decrypt `buf` in place, as if it started at `offset` in the audio data.
+    fn decrypt(&self, offset: u64, buf: &Bound<'_, PyByteArray>) {
+        // Safety: no Python code runs while the slice is alive.
+        self.0.decrypt(offset, unsafe { buf.as_bytes_mut() });
+    }
+}
+
+#[pymodule]
+fn qmc2(m: &Bound<'_, PyModule>) -> PyResult<()> {
+    m.add(
+        "RECOMMENDED_DETECTION_SIZE",
+        crypto::detection::RECOMMENDED_DETECTION_SIZE,
+    )?;
+    m.add("QMC2Error", m.py().get_type::<QMC2Error>())?;
+    m.add_class::<Detection>()?;
+    m.add_class::<Decryptor>()?;
+    m.add_class::<QMC2File>()?;
+    m.add_function(wrap_pyfunction!(detect, m)?)?;
+    m.add_function(wrap_pyfunction!(parse_ekey, m)?)?;
+    Ok(())
+}
@@ -0,0 +1,116 @@
+"""Run with `maturin develop && python -m unittest discover tests`."""
+
+import io
+import os
+import subprocess
+import tempfile
+import unittest
+
+import qmc2
+
+
+def make_fixture(directory, plain_path, key_len):
+    fixture_path = os.path.join(directory, f"test-{key_len}.mflac")
+    subprocess.run(
+        ["cargo", "run", "-q", "-p", "qmc2-crypto", "--features", "qmc2-crypto/test-support",
+         "--example", "make_fixture", "--",
+         plain_path, fixture_path, str(key_len)],
+        check=True,
+    )
+    with open(fixture_path, "rb") as f:
+        return f.read()
+
+
+class QMC2Test(unittest.TestCase):
+    @classmethod
+    def setUpClass(cls):
+        cls.plain = b"fLaC" + os.urandom(300000)
+        with tempfile.TemporaryDirectory() as directory:
+            plain_path = os.path.join(directory, "plain.flac")
+            with open(plain_path, "wb") as f:
+                f.write(cls.plain)
+            cls.fixtures = [make_fixture(directory, plain_path, n) for n in (512, 256)]
+
+    def test_detect_and_decrypt(self):
+        for fixture in self.fixtures:
+            tail = fixture[-qmc2.RECOMMENDED_DETECTION_SIZE:]
+            detection = qmc2.detect(tail)
+            self.assertEqual(detection.song_id, "12345")
+
+            ekey_start = len(fixture) - len(tail) + detection.ekey_position
+            ekey = fixture[ekey_start:ekey_start + detection.ekey_len].decode()
+            audio = bytearray(fixture[:len(fixture) - len(tail) + detection.eof_position])
+            qmc2.Decryptor(ekey).decrypt(0, audio)
+            self.assertEqual(audio, self.plain)
+
+            audio = bytearray(fixture[1000:2000])
+            qmc2.Decryptor.from_key(qmc2.parse_ekey(ekey)).decrypt(1000, audio)
+            self.assertEqual(audio, self.plain[1000:2000])
+
+    def test_file(self):
+        with qmc2.QMC2File(io.BytesIO(self.fixtures[0])) as f:
+            self.assertEqual(f.song_id, "12345")
+            self.assertEqual(f.audio_size, len(self.plain))
+            self.assertEqual(f.read(4), b"fLaC")
+            self.assertEqual(f.tell(), 4)
+            self.assertEqual(f.read(), self.plain[4:])
+            self.assertEqual(f.read(), b"")
+
+            self.assertEqual(f.seek(-10, io.SEEK_END), len(self.plain) - 10)
+            self.assertEqual(f.read(100), self.plain[-10:])
+            f.seek(5000)
+            f.seek(-100, io.SEEK_CUR)
+            buf = bytearray(50)
+            self.assertEqual(f.readinto(buf), 50)
+            self.assertEqual(buf, self.plain[4900:4950])
+        self.assertTrue(f.closed)
+        with self.assertRaises(ValueError):
+            f.read()
+
+    def test_buffered_reader(self):
+        with io.BufferedReader(qmc2.QMC2File(io.BytesIO(self.fixtures[1])), 4096) as f:
+            self.assertEqual(f.read(4), b"fLaC")
+            self.assertEqual(f.peek(1)[:1], self.plain[4:5])
+            f.seek(100000)
+            self.assertEqual(f.read(10000), self.plain[100000:110000])
+            self.assertEqual(f.read(), self.plain[110000:])
+
+    def test_readinto_buffers(self):
+        with qmc2.QMC2File(io.BytesIO(self.fixtures[0])) as f:
+            buf = memoryview(bytearray(100))[10:60]
+            self.assertEqual(f.readinto(buf), 50)
+            self.assertEqual(buf, self.plain[:50])
+
+            with self.assertRaises(TypeError):
+                f.readinto(b"read-only")
+            with self.assertRaises(TypeError):
+                f.readinto(memoryview(bytearray(100))[::2])
+
+    def test_errors(self):
+        with self.assertRaises(qmc2.QMC2Error) as cm:
+            qmc2.detect(b"\xff" * 16)
+        self.assertEqual(cm.exception.code, "UNKNOWN_MAGIC")
+
+        with self.assertRaises(qmc2.QMC2Error) as cm:
+            qmc2.parse_ekey("!!")
+        self.assertEqual(cm.exception.code, "EKEY_PARSE")
+
+        with self.assertRaises(qmc2.QMC2Error) as cm:
+            qmc2.QMC2File(io.BytesIO(b"\0" * 100))
+        self.assertEqual(cm.exception.code, "ZEROS_AT_EOF")
+
+        with self.assertRaises(qmc2.QMC2Error) as cm:
+            qmc2.Decryptor.from_key(b"1234567")
+        self.assertEqual(cm.exception.code, "INVALID_KEY_LENGTH")
+
+    def test_file_object_errors_pass_through(self):
+        class Broken(io.BytesIO):
+            def read(self, size=-1):
+                raise RuntimeError("broken")
+
+        with self.assertRaises(RuntimeError):
+            qmc2.QMC2File(Broken(self.fixtures[0]))
+
+
+if __name__ == "__main__":
+    unittest.main()
@@ -15,13 +15,20 @@ fi
 cp pkg/web/qmc2_crypto_bg.wasm npm/
 cp pkg/web/qmc2_crypto.js npm/qmc2_crypto.mjs
 cp pkg/web/*.ts npm/
+rm -rf npm/snippets
+cp -R pkg/web/snippets npm/
+
+# The error classes live in a snippet; export them alongside everything else.
+for snippet in pkg/web/snippets/*/src/errors.js; do
+  echo "export { QMC2Error, QMC2DetectionError, QMC2KeyError } from './${snippet#pkg/web/}';" >> npm/qmc2_crypto.mjs
+done
 
 TEMPLATE="$(env \
   WASM_B64="$(base64 --wrap=0 <pkg/web/qmc2_crypto_bg.wasm)" \
   envsubst < support/loader_template.js
 )"
 
-awk -v template="$TEMPLATE" -f support/loader_generate.awk < pkg/web/qmc2_crypto.js > npm/qmc2_crypto_embed.js
+awk -v template="$TEMPLATE" -v pkg_dir=pkg/web -f support/loader_generate.awk < pkg/web/qmc2_crypto.js > npm/qmc2_crypto_embed.js
 awk -f support/type_filter.awk < pkg/web/qmc2_crypto.d.ts > npm/qmc2_crypto_embed.d.ts
 cp npm/qmc2_crypto_embed.js ../public/
 
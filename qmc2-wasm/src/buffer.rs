@@ -0,0 +1,64 @@
+use crate::errors::length_error;
+use js_sys::Uint8Array;
+use wasm_bindgen::prelude::*;
+
+/// A buffer that lives in wasm memory, so blocks can be decrypted in place
+/// without copying them into and out of the module on every call.
+///
+/// Lifetime rules:
+///
+/// - Get one with `alloc_buffer(size)`, and release it with `free()` once
+///   done; it is not garbage collected.
+/// - `view()` is a `Uint8Array` over the buffer itself, not a copy. Any call
+///   into the module may grow wasm memory, which detaches every existing
+///   view, so call `view()` again after each such call instead of keeping
+///   the view around.
+/// - Views must not be used after `free()`.
+///
+/// ```js
+/// const buffer = QMCCrypto.alloc_buffer(crypto.get_recommended_block_size());
+/// for (let offset = 0; offset < audioSize; offset += n) {
+///   buffer.view().set(readBlock(offset));
+///   crypto.decrypt_buffer(offset, buffer, n);
+///   writeBlock(buffer.view().subarray(0, n));
+/// }
+/// buffer.free();
+/// ```
+#[wasm_bindgen]
+pub struct QMC2Buffer {
+    data: Vec<u8>,
+}
+
+impl QMC2Buffer {
+    /// The first `len` bytes of the buffer.
+    pub(crate) fn slice_mut(&mut self, len: usize) -> Result<&mut [u8], JsValue> {
+        let capacity = self.data.len();
+        self.data
+            .get_mut(..len)
+            .ok_or_else(|| length_error(len, capacity))
+    }
+}
+
+#[wasm_bindgen]
+impl QMC2Buffer {
+    /// A view over the buffer; see the lifetime rules above.
+    #[wasm_bindgen]
+    pub fn view(&self) -> Uint8Array {
+        // Safety: the view is only valid until wasm memory grows or the buffer
+        // is freed, which is what the lifetime rules tell callers.
+        unsafe { Uint8Array::view(&self.data) }
+    }
+
+    #[wasm_bindgen]
+    pub fn get_size(&self) -> usize {
+        self.data.len()
+    }
+}
+
+/// Allocate a zeroed `QMC2Buffer` of `size` bytes inside wasm memory.
+#[wasm_bindgen]
+pub fn alloc_buffer(size: usize) -> QMC2Buffer {
+    QMC2Buffer {
+        data: vec![0u8; size],
+    }
+}
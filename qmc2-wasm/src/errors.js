@@ -0,0 +1,29 @@
+/**
+ * Thrown by every function of this module; tell them apart with `instanceof`
+ * and `code`.
+ */
+export class QMC2Error extends Error {
+    constructor(message, code) {
+        super(message);
+        this.code = code;
+    }
+}
+QMC2Error.prototype.name = "QMC2Error";
+
+/**
+ * The trailer at the end of the file could not be made sense of.
+ */
+export class QMC2DetectionError extends QMC2Error {}
+QMC2DetectionError.prototype.name = "QMC2DetectionError";
+
+/**
+ * The ekey could not be decoded, or a key was too short to be used.
+ */
+export class QMC2KeyError extends QMC2Error {}
+QMC2KeyError.prototype.name = "QMC2KeyError";
+
+const errorClasses = { QMC2Error, QMC2DetectionError, QMC2KeyError };
+
+export function createError(name, message, code) {
+    return new errorClasses[name](message, code);
+}
@@ -0,0 +1,166 @@
+use js_sys::Reflect;
+use qmc2_crypto::errors::{DecryptError, DetectionError};
+use wasm_bindgen::prelude::*;
+
+#[wasm_bindgen(module = "/src/errors.js")]
+extern "C" {
+    /// An instance of the `QMC2Error` subclass called `name`.
+    #[wasm_bindgen(js_name = createError)]
+    fn create_error(name: &str, message: &str, code: &str) -> js_sys::Error;
+}
+
+#[wasm_bindgen(typescript_custom_section)]
+const TS_ERRORS: &'static str = r#"
+export type QMC2ErrorCode =
+  | "IO"
+  | "BUFFER_TOO_SMALL"
+  | "EKEY_END_NOT_FOUND"
+  | "POSITION_OUT_OF_RANGE"
+  | "SONG_ID_OVERFLOW"
+  | "ZEROS_AT_EOF"
+  | "UNKNOWN_MAGIC"
+  | "EKEY_PARSE"
+  | "KEY_DERIVE"
+  | "EKEY_NOT_UTF8"
+  | "START_OUT_OF_RANGE"
+  | "CANCELLED"
+  | "OFFSET_OUT_OF_RANGE"
+  | "INVALID_KEY_LENGTH"
+  | "LENGTH_OUT_OF_RANGE"
+  | "EKEY_TOO_LONG"
+  | "UNKNOWN_FORMAT"
+  | "INVALID_PADDING"
+  | "NOT_SEEKABLE"
+  | "UNEXPECTED_EKEY"
+  | "RANDOM_UNAVAILABLE";
+
+/**
+ * Thrown by every function of this module; tell them apart with `instanceof`
+ * and `code`.
+ */
+export class QMC2Error extends Error {
+  name: "QMC2Error" | "QMC2DetectionError" | "QMC2KeyError";
+  readonly code: QMC2ErrorCode;
+}
+
+/**
+ * The trailer at the end of the file could not be made sense of.
+ */
+export class QMC2DetectionError extends QMC2Error {
+  name: "QMC2DetectionError";
+  /** With `UNKNOWN_MAGIC`: the last 4 bytes of the file, read as little-endian. */
+  readonly magic?: number;
+}
+
+/**
+ * The ekey could not be decoded, or (with `INVALID_KEY_LENGTH`) a key was
+ * too short to be used, or (with `EKEY_TOO_LONG`) would not fit a v1 trailer.
+ */
+export class QMC2KeyError extends QMC2Error {
+  name: "QMC2KeyError";
+  /** With `INVALID_KEY_LENGTH`. */
+  readonly length?: number;
+}
+
+/**
+ * With `START_OUT_OF_RANGE`.
+ */
+export interface QMC2RangeError extends QMC2Error {
+  name: "QMC2Error";
+  start: number;
+  audioLen: number;
+}
+
+/**
+ * With `OFFSET_OUT_OF_RANGE`: an offset or size was not a whole number
+ * between 0 and `Number.MAX_SAFE_INTEGER`.
+ */
+export interface QMC2OffsetError extends QMC2Error {
+  name: "QMC2Error";
+  value: number;
+}
+
+/**
+ * With `LENGTH_OUT_OF_RANGE`: asked to work on more bytes than a
+ * `QMC2Buffer` holds.
+ */
+export interface QMC2LengthError extends QMC2Error {
+  name: "QMC2Error";
+  length: number;
+  capacity: number;
+}
+"#;
+
+/// Turn an error from the core into one of the `Error` subclasses of
+/// `errors.js`, with a stable `code` and whatever numbers help explain it, as
+/// described by `TS_ERRORS`.
+pub(crate) fn js_error<E: Into<DecryptError>>(err: E) -> JsValue {
+    let err = err.into();
+
+    let (name, details): (&str, Vec<(&str, f64)>) = match &err {
+        DecryptError::Detection(DetectionError::UnknownMagicLE32(magic)) => {
+            ("QMC2DetectionError", vec![("magic", *magic as f64)])
+        }
+        DecryptError::Detection(_) => ("QMC2DetectionError", vec![]),
+        DecryptError::InvalidKeyLength(len) => ("QMC2KeyError", vec![("length", *len as f64)]),
+        DecryptError::Crypto(_) | DecryptError::EKeyNotUtf8 | DecryptError::EKeyTooLongForV1(_) => {
+            ("QMC2KeyError", vec![])
+        }
+        DecryptError::StartOutOfRange { start, audio_len } => (
+            "QMC2Error",
+            vec![("start", *start as f64), ("audioLen", *audio_len as f64)],
+        ),
+        _ => ("QMC2Error", vec![]),
+    };
+
+    new_error(name, &err.to_string(), err.code(), &details)
+}
+
+/// Offsets and sizes come in as JS numbers, which are only exact up to
+/// `Number.MAX_SAFE_INTEGER`; anything else is refused rather than rounded.
+pub(crate) fn to_offset(value: f64) -> Result<u64, JsValue> {
+    const MAX_SAFE_INTEGER: f64 = 9007199254740991.0;
+
+    if (0.0..=MAX_SAFE_INTEGER).contains(&value) && value.fract() == 0.0 {
+        Ok(value as u64)
+    } else {
+        Err(new_error(
+            "QMC2Error",
+            &format!("{} is not a valid offset", value),
+            "OFFSET_OUT_OF_RANGE",
+            &[("value", value)],
+        ))
+    }
+}
+
+/// Keys start with an 8-byte header that is kept as is in the ekey.
+pub(crate) fn check_key_len(len: usize) -> Result<(), JsValue> {
+    if len >= 8 {
+        Ok(())
+    } else {
+        Err(js_error(DecryptError::InvalidKeyLength(len)))
+    }
+}
+
+pub(crate) fn length_error(len: usize, capacity: usize) -> JsValue {
+    new_error(
+        "QMC2Error",
+        &format!("{} bytes asked for, but the buffer holds {}", len, capacity),
+        "LENGTH_OUT_OF_RANGE",
+        &[("length", len as f64), ("capacity", capacity as f64)],
+    )
+}
+
+/// No source of random numbers (`crypto.getRandomValues`) to make a key with.
+pub(crate) fn random_error(err: getrandom::Error) -> JsValue {
+    new_error("QMC2Error", &err.to_string(), "RANDOM_UNAVAILABLE", &[])
+}
+
+fn new_error(name: &str, message: &str, code: &str, details: &[(&str, f64)]) -> JsValue {
+    let error = create_error(name, message, code);
+    // Setting a property on a fresh `Error` cannot fail.
+    for &(key, value) in details {
+        let _ = Reflect::set(&error, &key.into(), &value.into());
+    }
+    error.into()
+}
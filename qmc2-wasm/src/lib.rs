@@ -1,8 +1,16 @@
+mod buffer;
+mod errors;
 mod utils;
 
+pub use buffer::{alloc_buffer, QMC2Buffer};
+
+use errors::{check_key_len, js_error, random_error, to_offset};
+
 use qmc2_crypto as qmc2;
 use qmc2_crypto::detection::Detection;
-use qmc2_crypto::QMC2Crypto;
+use qmc2_crypto::errors::DetectionError;
+use qmc2_crypto::sniff::{AudioFormat, SNIFF_SIZE};
+use qmc2_crypto::{CipherKind, QMC2Crypto, StreamDecryptor};
 use wasm_bindgen::prelude::*;
 
 // When the `wee_alloc` feature is enabled, use `wee_alloc` as the global
@@ -14,9 +22,9 @@ static ALLOC: wee_alloc::WeeAlloc = wee_alloc::WeeAlloc::INIT;
 #[wasm_bindgen]
 pub struct DetectionWrapper {
     #[wasm_bindgen]
-    pub eof_position: i32,
+    pub eof_position: f64,
     #[wasm_bindgen]
-    pub ekey_position: i32,
+    pub ekey_position: f64,
     #[wasm_bindgen]
     pub ekey_len: usize,
     song_id: String,
@@ -25,8 +33,8 @@ pub struct DetectionWrapper {
 impl DetectionWrapper {
     pub(crate) fn from(d: Detection) -> Self {
         DetectionWrapper {
-            eof_position: d.eof_position as i32,
-            ekey_position: d.ekey_position as i32,
+            eof_position: d.eof_position as f64,
+            ekey_position: d.ekey_position as f64,
             ekey_len: d.ekey_len,
             song_id: d.song_id,
         }
@@ -46,11 +54,11 @@ pub fn get_recommended_detection_size() -> usize {
     qmc2::detection::RECOMMENDED_DETECTION_SIZE
 }
 
-#[wasm_bindgen(catch)]
+#[wasm_bindgen]
 pub fn detect(buf: &[u8]) -> Result<DetectionWrapper, JsValue> {
     qmc2::detection::detect(buf)
         .map(DetectionWrapper::from)
-        .map_err(|e| JsValue::from(e.to_string()))
+        .map_err(js_error)
 }
 
 #[wasm_bindgen]
@@ -63,17 +71,245 @@ impl QMC2CryptoWrapper {
         self.0.get_recommended_block_size()
     }
 
+    /// `offset` can go beyond 4 GiB, up to `Number.MAX_SAFE_INTEGER`.
+    #[wasm_bindgen]
+    pub fn decrypt(&self, offset: f64, buf: &mut [u8]) -> Result<(), JsValue> {
+        self.0.decrypt(to_offset(offset)?, buf);
+        Ok(())
+    }
+
+    /// Encrypt `buf` in place, as if it started at `offset` in the audio data.
+    #[wasm_bindgen]
+    pub fn encrypt(&self, offset: f64, buf: &mut [u8]) -> Result<(), JsValue> {
+        self.0.encrypt(to_offset(offset)?, buf);
+        Ok(())
+    }
+
+    /// Like `decrypt`, on the first `len` bytes of `buffer`, without copying.
+    #[wasm_bindgen]
+    pub fn decrypt_buffer(
+        &self,
+        offset: f64,
+        buffer: &mut QMC2Buffer,
+        len: usize,
+    ) -> Result<(), JsValue> {
+        self.0.decrypt(to_offset(offset)?, buffer.slice_mut(len)?);
+        Ok(())
+    }
+
+    /// Like `encrypt`, on the first `len` bytes of `buffer`, without copying.
     #[wasm_bindgen]
-    pub fn decrypt(&self, offset: usize, buf: &mut [u8]) {
-        self.0.decrypt(offset, buf)
+    pub fn encrypt_buffer(
+        &self,
+        offset: f64,
+        buffer: &mut QMC2Buffer,
+        len: usize,
+    ) -> Result<(), JsValue> {
+        self.0.encrypt(to_offset(offset)?, buffer.slice_mut(len)?);
+        Ok(())
     }
 }
 
-#[wasm_bindgen(catch)]
+#[wasm_bindgen]
 pub fn decrypt_factory(ekey: String) -> Result<QMC2CryptoWrapper, JsValue> {
     qmc2::decrypt_factory(ekey.as_str())
         .map(QMC2CryptoWrapper)
-        .map_err(|e| JsValue::from(e.to_string()))
+        .map_err(js_error)
+}
+
+/// A random key of `len` bytes (at least 8). Keys longer than 300 bytes
+/// use the RC4 cipher, shorter ones the map cipher.
+#[wasm_bindgen]
+pub fn generate_key(len: usize) -> Result<Vec<u8>, JsValue> {
+    check_key_len(len)?;
+    let mut key = vec![0u8; len];
+    getrandom::getrandom(&mut key).map_err(random_error)?;
+    Ok(key)
+}
+
+/// Encrypt a key into the ekey stored in file trailers.
+#[wasm_bindgen]
+pub fn generate_ekey(key: &[u8]) -> Result<String, JsValue> {
+    check_key_len(key.len())?;
+    Ok(qmc2::generate_ekey(key))
+}
+
+/// The cipher for a plain (not encrypted) key, e.g. from `generate_key`.
+#[wasm_bindgen]
+pub fn crypto_from_key(key: &[u8]) -> Result<QMC2CryptoWrapper, JsValue> {
+    qmc2::crypto_from_key(key)
+        .map(QMC2CryptoWrapper)
+        .map_err(js_error)
+}
+
+/// The QTag trailer to append after the encrypted audio.
+#[wasm_bindgen]
+pub fn build_qtag_trailer(ekey: &str, song_id: &str) -> Vec<u8> {
+    qmc2::trailer::build_qtag_trailer(ekey, song_id)
+}
+
+#[wasm_bindgen]
+pub fn get_recommended_tail_size() -> usize {
+    qmc2::stream::RECOMMENDED_TAIL_SIZE
+}
+
+/// Decrypts a file fed in order, chunk by chunk, so it can be wrapped in a
+/// `TransformStream` and fed from `File.stream()` without holding the whole
+/// file in memory.
+#[wasm_bindgen]
+pub struct QMC2StreamDecryptor {
+    inner: StreamDecryptor,
+    song_id: String,
+}
+
+#[wasm_bindgen]
+impl QMC2StreamDecryptor {
+    /// Decrypt with a known ekey; `audio_size` is the file size without its trailer.
+    #[wasm_bindgen(constructor)]
+    pub fn new(ekey: String, audio_size: f64) -> Result<QMC2StreamDecryptor, JsValue> {
+        let audio_size = to_offset(audio_size)?;
+        let crypto = qmc2::decrypt_factory(ekey.as_str()).map_err(js_error)?;
+        Ok(QMC2StreamDecryptor {
+            inner: StreamDecryptor::new(crypto, audio_size),
+            song_id: "".into(),
+        })
+    }
+
+    /// Set up from the size of the file and its last
+    /// `get_recommended_tail_size()` bytes (or all of it, if smaller).
+    #[wasm_bindgen]
+    pub fn from_tail(file_size: f64, tail: &[u8]) -> Result<QMC2StreamDecryptor, JsValue> {
+        let tail = qmc2::stream::parse_tail(to_offset(file_size)?, tail).map_err(js_error)?;
+        let inner = StreamDecryptor::from_tail(&tail).map_err(js_error)?;
+        Ok(QMC2StreamDecryptor {
+            inner,
+            song_id: tail.detection.song_id,
+        })
+    }
+
+    /// Decrypt the next chunk of the file.
+    ///
+    /// Once the trailer is reached, the result is shorter than the chunk, or empty.
+    #[wasm_bindgen]
+    pub fn transform(&mut self, chunk: &[u8]) -> Vec<u8> {
+        let mut buf = chunk.to_vec();
+        let len = self.inner.decrypt_chunk(&mut buf);
+        buf.truncate(len);
+        buf
+    }
+
+    /// Like `transform`, on the first `len` bytes of `buffer`, without copying.
+    ///
+    /// Returns how many bytes at the start of `buffer` are audio.
+    #[wasm_bindgen]
+    pub fn transform_buffer(
+        &mut self,
+        buffer: &mut QMC2Buffer,
+        len: usize,
+    ) -> Result<usize, JsValue> {
+        Ok(self.inner.decrypt_chunk(buffer.slice_mut(len)?))
+    }
+
+    #[wasm_bindgen]
+    pub fn get_song_id(&self) -> String {
+        self.song_id.clone()
+    }
+
+    #[wasm_bindgen]
+    pub fn get_audio_size(&self) -> f64 {
+        self.inner.audio_len() as f64
+    }
+
+    /// Bytes fed so far.
+    #[wasm_bindgen]
+    pub fn get_position(&self) -> f64 {
+        self.inner.position() as f64
+    }
+
+    #[wasm_bindgen]
+    pub fn is_done(&self) -> bool {
+        self.inner.is_done()
+    }
+}
+
+/// Everything `decrypt_file` found out about a file, along with its audio.
+#[wasm_bindgen]
+pub struct DecryptResult {
+    data: Vec<u8>,
+    format: AudioFormat,
+    cipher: CipherKind,
+    song_id: String,
+    #[wasm_bindgen]
+    pub audio_size: f64,
+    #[wasm_bindgen]
+    pub trailer_size: f64,
+    #[wasm_bindgen]
+    pub ekey_len: usize,
+}
+
+#[wasm_bindgen]
+impl DecryptResult {
+    /// The decrypted audio, moved out rather than copied: only the first
+    /// call returns it, later ones get an empty array.
+    #[wasm_bindgen]
+    pub fn take_data(&mut self) -> Vec<u8> {
+        std::mem::take(&mut self.data)
+    }
+
+    /// File extension for the audio, without the leading dot (`"bin"` if unknown).
+    #[wasm_bindgen]
+    pub fn get_extension(&self) -> String {
+        self.format.extension().into()
+    }
+
+    #[wasm_bindgen]
+    pub fn get_mime_type(&self) -> String {
+        self.format.mime_type().into()
+    }
+
+    #[wasm_bindgen]
+    pub fn get_song_id(&self) -> String {
+        self.song_id.clone()
+    }
+
+    /// `"map"` or `"rc4"`.
+    #[wasm_bindgen]
+    pub fn get_cipher(&self) -> String {
+        match self.cipher {
+            CipherKind::Map => "map",
+            CipherKind::RC4 => "rc4",
+        }
+        .into()
+    }
+}
+
+/// Detect, decrypt and identify a whole file in one go.
+///
+/// The file is decrypted over itself and cut down to the audio, which
+/// `take_data` then hands back without another copy.
+#[wasm_bindgen]
+pub fn decrypt_file(mut bytes: Vec<u8>) -> Result<DecryptResult, JsValue> {
+    let file_len = bytes.len() as u64;
+    let tail = qmc2::stream::parse_tail(file_len, &bytes).map_err(js_error)?;
+    if tail.audio_len > file_len {
+        return Err(js_error(DetectionError::PositionOutOfRange));
+    }
+    let key = qmc2::parse_ekey(&tail.ekey).map_err(js_error)?;
+    let crypto = qmc2::crypto_from_key(&key).map_err(js_error)?;
+
+    bytes.truncate(tail.audio_len as usize);
+    crypto.decrypt(0, &mut bytes);
+    let format = AudioFormat::sniff(&bytes[..std::cmp::min(bytes.len(), SNIFF_SIZE)]);
+
+    Ok(DecryptResult {
+        data: bytes,
+        format,
+        cipher: CipherKind::for_key(&key),
+        audio_size: tail.audio_len as f64,
+        trailer_size: (file_len - tail.audio_len) as f64,
+        ekey_len: tail.detection.ekey_len,
+        song_id: tail.detection.song_id,
+    })
 }
 
 #[wasm_bindgen]
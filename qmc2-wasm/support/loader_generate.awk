@@ -31,6 +31,26 @@ function indent(str, tmp,i,result) {
     return substr(result, 2)
 }
 
+function add_export(name) {
+    export_glue = export_glue \
+        "\n" export_indent "Object.defineProperty(exports, '" name "', {" \
+        "\n" export_indent "    get: function () { return __last_inst." name "; }" \
+        "\n" export_indent "});"
+}
+
+# JS snippets (such as the error classes) are imported by the generated code;
+# paste them in instead, as the embedded loader is a single file.
+function inline_snippet(path, line,m) {
+    while ((getline line < path) > 0) {
+        if (match(line, /^export class (\w+)/, m)) {
+            export_buf = export_buf "\n" export_indent "exports." m[1] " = " m[1] ";"
+            add_export(m[1])
+        }
+        print code_indent gensub(/^export /, "", 1, line)
+    }
+    close(path)
+}
+
 BEGIN {
     code_indent=sprintf("%12s","");
     export_indent=sprintf("%8s","");
@@ -64,10 +84,7 @@ BEGIN {
             export_buf = export_buf "\n" export_indent gensub(/^export /, "", 1)
 
             # Generate glue
-            export_glue = export_glue \
-                "\n" export_indent "Object.defineProperty(exports, '" export_name "', {" \
-                "\n" export_indent "    get: function () { return __last_inst." export_name "; }" \
-                "\n" export_indent "});"
+            add_export(export_name)
         }
         docblock_buf = ""
     } else if (is_doc) {
@@ -80,6 +97,8 @@ BEGIN {
     } else if ($0 == "/**") {
         is_doc=1
         docblock_buf = code_indent $0
+    } else if (match($0, /^import .* from '\.\/(snippets\/[^']+)'/, m)) {
+        inline_snippet(pkg_dir "/" m[1])
     } else if (/^export default/ || /^(let )?cachedTextDecoder/) {
         print code_indent "// " $0
     } else if (/import.meta.url/) {
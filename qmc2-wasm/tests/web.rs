@@ -3,11 +3,223 @@
 #![cfg(target_arch = "wasm32")]
 
 extern crate wasm_bindgen_test;
+use js_sys::Reflect;
+use qmc2_crypto::test_util::{qtag_file, test_audio, test_key, SONG_ID};
+use qmc2_wasm::*;
+use wasm_bindgen::prelude::*;
 use wasm_bindgen_test::*;
 
+#[wasm_bindgen(module = "/src/errors.js")]
+extern "C" {
+    type QMC2Error;
+    type QMC2DetectionError;
+    type QMC2KeyError;
+}
+
 wasm_bindgen_test_configure!(run_in_browser);
 
+fn property(err: &JsValue, key: &str) -> JsValue {
+    Reflect::get(err, &key.into()).unwrap()
+}
+
+fn code_of(err: &JsValue) -> String {
+    property(err, "code").as_string().unwrap()
+}
+
+#[wasm_bindgen_test]
+fn stream_decryptor_any_chunk_size() {
+    let audio = test_audio(b"fLaC", 20000);
+    let file = qtag_file(&audio, &test_key(512));
+
+    for chunk_size in [1, 777, 4096, file.len()] {
+        let tail_start = file.len().saturating_sub(get_recommended_tail_size());
+        let mut decryptor =
+            QMC2StreamDecryptor::from_tail(file.len() as f64, &file[tail_start..]).unwrap();
+        assert_eq!(decryptor.get_audio_size(), 20000.0);
+        assert_eq!(decryptor.get_song_id(), SONG_ID);
+
+        let mut output = vec![];
+        for chunk in file.chunks(chunk_size) {
+            output.extend(decryptor.transform(chunk));
+        }
+        assert!(decryptor.is_done());
+        assert_eq!(decryptor.get_position(), file.len() as f64);
+        assert_eq!(output, audio);
+    }
+}
+
+#[wasm_bindgen_test]
+fn stream_decryptor_with_known_ekey() {
+    let audio = test_audio(b"fLaC", 1000);
+    let key = test_key(128);
+    let file = qtag_file(&audio, &key);
+
+    let mut decryptor =
+        QMC2StreamDecryptor::new(generate_ekey(&key).unwrap(), audio.len() as f64).unwrap();
+    // The trailer is dropped, even when it shares a chunk with the audio.
+    let mut output = decryptor.transform(&file[..600]);
+    output.extend(decryptor.transform(&file[600..]));
+    assert_eq!(output, audio);
+    assert_eq!(decryptor.get_song_id(), "");
+}
+
+#[wasm_bindgen_test]
+fn stream_decryptor_tail_too_short() {
+    let file = qtag_file(&test_audio(b"fLaC", 100), &test_key(512));
+    let tail_start = file.len() - get_recommended_detection_size();
+    let err = QMC2StreamDecryptor::from_tail(file.len() as f64, &file[tail_start..])
+        .err()
+        .unwrap();
+    assert_eq!(code_of(&err), "BUFFER_TOO_SMALL");
+}
+
+#[wasm_bindgen_test]
+fn decrypt_file_result() {
+    let audio = test_audio(b"fLaC", 30000);
+    let file = qtag_file(&audio, &test_key(512));
+
+    let mut result = decrypt_file(file.clone()).unwrap();
+    assert_eq!(result.take_data(), audio);
+    assert!(result.take_data().is_empty());
+    assert_eq!(result.get_extension(), "flac");
+    assert_eq!(result.get_mime_type(), "audio/flac");
+    assert_eq!(result.get_song_id(), SONG_ID);
+    assert_eq!(result.get_cipher(), "rc4");
+    assert_eq!(result.audio_size, 30000.0);
+    assert_eq!(result.trailer_size, (file.len() - audio.len()) as f64);
+    assert_eq!(
+        result.ekey_len,
+        generate_ekey(&test_key(512)).unwrap().len()
+    );
+
+    let result = decrypt_file(qtag_file(&audio, &test_key(128))).unwrap();
+    assert_eq!(result.get_cipher(), "map");
+}
+
+#[wasm_bindgen_test]
+fn error_codes() {
+    let err = decrypt_file(vec![0xff; 100]).err().unwrap();
+    assert_eq!(code_of(&err), "UNKNOWN_MAGIC");
+    assert_eq!(property(&err, "name"), "QMC2DetectionError");
+    assert_eq!(property(&err, "magic"), 0xffffffffu32 as f64);
+    assert!(err.is_instance_of::<QMC2DetectionError>());
+    assert!(err.is_instance_of::<QMC2Error>());
+    assert!(err.is_instance_of::<js_sys::Error>());
+
+    let err = detect(&[0; 4]).err().unwrap();
+    assert_eq!(code_of(&err), "BUFFER_TOO_SMALL");
+
+    let err = decrypt_factory("not an ekey".into()).err().unwrap();
+    assert_eq!(property(&err, "name"), "QMC2KeyError");
+    assert!(err.is_instance_of::<QMC2KeyError>());
+
+    let err = generate_key(7).err().unwrap();
+    assert_eq!(code_of(&err), "INVALID_KEY_LENGTH");
+    assert_eq!(property(&err, "length"), 7.0);
+    assert_eq!(
+        code_of(&crypto_from_key(&[1; 7]).err().unwrap()),
+        "INVALID_KEY_LENGTH"
+    );
+    assert_eq!(
+        code_of(&generate_ekey(&[1; 7]).err().unwrap()),
+        "INVALID_KEY_LENGTH"
+    );
+}
+
+#[wasm_bindgen_test]
+fn offsets_beyond_4gib() {
+    let key = test_key(512);
+    let crypto = crypto_from_key(&key).unwrap();
+    let expected = qmc2_crypto::crypto_from_key(&key).unwrap();
+
+    for offset in [(1u64 << 32) + 5, (1 << 40) + 12345, (1 << 53) - 1] {
+        let mut buf = test_audio(b"fLaC", 1000);
+        let mut want = buf.clone();
+        crypto.decrypt(offset as f64, &mut buf).unwrap();
+        expected.decrypt(offset, &mut want);
+        assert_eq!(buf, want);
+    }
+
+    let mut buf = [0u8; 16];
+    for offset in [-1.0, 1.5, 9007199254740992.0, f64::NAN] {
+        let err = crypto.decrypt(offset, &mut buf).err().unwrap();
+        assert_eq!(code_of(&err), "OFFSET_OUT_OF_RANGE");
+    }
+
+    let err = QMC2StreamDecryptor::new(generate_ekey(&key).unwrap(), -1.0)
+        .err()
+        .unwrap();
+    assert_eq!(code_of(&err), "OFFSET_OUT_OF_RANGE");
+}
+
+#[wasm_bindgen_test]
+fn generate_and_encrypt() {
+    let key = generate_key(512).unwrap();
+    assert_eq!(key.len(), 512);
+    assert_ne!(key, generate_key(512).unwrap());
+
+    let ekey = generate_ekey(&key).unwrap();
+    let crypto = decrypt_factory(ekey.clone()).unwrap();
+    let audio = test_audio(b"fLaC", 5000);
+    let mut data = audio.clone();
+    crypto.encrypt(1000.0, &mut data).unwrap();
+    assert_ne!(data, audio);
+    crypto.decrypt(1000.0, &mut data).unwrap();
+    assert_eq!(data, audio);
+
+    let trailer = build_qtag_trailer(&ekey, SONG_ID);
+    assert!(trailer.ends_with(b"QTag"));
+    let detection = detect(&trailer).unwrap();
+    assert_eq!(detection.get_song_id(), SONG_ID);
+    assert_eq!(detection.ekey_len, ekey.len());
+}
+
+#[wasm_bindgen_test]
+fn buffer_in_place() {
+    let audio = test_audio(b"fLaC", 20000);
+    let key = test_key(512);
+    let file = qtag_file(&audio, &key);
+    let crypto = crypto_from_key(&key).unwrap();
+
+    let mut buffer = alloc_buffer(4096);
+    assert_eq!(buffer.get_size(), 4096);
+    assert_eq!(buffer.view().length(), 4096);
+
+    buffer.view().copy_from(&file[..4096]);
+    crypto.decrypt_buffer(0.0, &mut buffer, 4096).unwrap();
+    assert_eq!(buffer.view().to_vec(), &audio[..4096]);
+
+    buffer.view().copy_from(&file[8192..12288]);
+    crypto.decrypt_buffer(8192.0, &mut buffer, 100).unwrap();
+    assert_eq!(buffer.view().to_vec()[..100], audio[8192..8292]);
+
+    let err = crypto.decrypt_buffer(0.0, &mut buffer, 4097).err().unwrap();
+    assert_eq!(code_of(&err), "LENGTH_OUT_OF_RANGE");
+    assert_eq!(property(&err, "capacity"), 4096.0);
+    assert!(err.is_instance_of::<QMC2Error>());
+    assert!(!err.is_instance_of::<QMC2KeyError>());
+    drop(buffer);
+}
+
 #[wasm_bindgen_test]
-fn pass() {
-    assert_eq!(1 + 1, 2);
+fn stream_decryptor_with_buffer() {
+    let audio = test_audio(b"fLaC", 10000);
+    let file = qtag_file(&audio, &test_key(128));
+    let tail_start = file.len().saturating_sub(get_recommended_tail_size());
+    let mut decryptor =
+        QMC2StreamDecryptor::from_tail(file.len() as f64, &file[tail_start..]).unwrap();
+
+    let mut buffer = alloc_buffer(3000);
+    let mut output: Vec<u8> = vec![];
+    for chunk in file.chunks(3000) {
+        buffer
+            .view()
+            .subarray(0, chunk.len() as u32)
+            .copy_from(chunk);
+        let len = decryptor
+            .transform_buffer(&mut buffer, chunk.len())
+            .unwrap();
+        output.extend(&buffer.view().to_vec()[..len]);
+    }
+    assert_eq!(output, audio);
 }